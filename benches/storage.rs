@@ -0,0 +1,130 @@
+// store/get throughput of the storage backends, single threaded and with several threads
+// sharing one storage like the message handlers do
+//
+// run with: cargo bench --bench storage 2>&1 >/dev/null
+// (results go to stderr, the storage's own logging goes to stdout)
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use kademlia::{
+    identity::Identity, memory_storage::MemoryStorage, record::Record, storage::SqlLiteStorage,
+    storage::Storage,
+};
+
+const OPERATIONS: usize = 5000;
+const BATCH_SIZE: usize = 500;
+const THREADS: usize = 8;
+
+fn main() {
+    let identity = Identity::generate();
+    let records: Vec<Record> = (0..OPERATIONS)
+        .map(|i| Record::new(&identity, format!("key-{i}"), format!("value-{i}")))
+        .collect();
+
+    let dir = std::env::temp_dir().join(format!("kade-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let sqlite =
+        Arc::new(SqlLiteStorage::new(dir.join("single.sqlite3").to_str().unwrap()).unwrap());
+    run("sqlite", &sqlite, &records);
+
+    let batched = SqlLiteStorage::new(dir.join("batched.sqlite3").to_str().unwrap()).unwrap();
+    let start = Instant::now();
+    for batch in records.chunks(BATCH_SIZE) {
+        batched.store_records(batch).unwrap();
+    }
+    report("sqlite store_records", OPERATIONS, start.elapsed());
+
+    let memory = Arc::new(MemoryStorage::new());
+    run("memory", &memory, &records);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn run<T: Storage + Send + Sync + 'static>(name: &str, storage: &Arc<T>, records: &[Record]) {
+    let start = Instant::now();
+    for record in records {
+        storage.store_record(record).unwrap();
+    }
+    report(
+        &format!("{name} store_record"),
+        records.len(),
+        start.elapsed(),
+    );
+
+    let start = Instant::now();
+    for record in records {
+        storage.get_record(&record.key).unwrap();
+    }
+    report(
+        &format!("{name} get_record"),
+        records.len(),
+        start.elapsed(),
+    );
+
+    // every thread reads the whole set, a lookup per get like the message handlers do
+    let keys: Arc<Vec<String>> = Arc::new(records.iter().map(|r| r.key.clone()).collect());
+    let start = Instant::now();
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let storage = Arc::clone(storage);
+            let keys = Arc::clone(&keys);
+            thread::spawn(move || {
+                for i in 0..keys.len() {
+                    storage.get_record(&keys[(i + t) % keys.len()]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    report(
+        &format!("{name} {THREADS} threads get_record"),
+        keys.len() * THREADS,
+        start.elapsed(),
+    );
+
+    // every thread writes its own slice and reads back the whole set, half stores half gets
+    let records = Arc::new(records.to_vec());
+    let start = Instant::now();
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let storage = Arc::clone(storage);
+            let records = Arc::clone(&records);
+            thread::spawn(move || {
+                for (i, record) in records.iter().enumerate() {
+                    if i % THREADS != t {
+                        continue;
+                    }
+                    storage.store_record(record).unwrap();
+                    storage
+                        .get_record(&records[(i * 7) % records.len()].key)
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    report(
+        &format!("{name} {THREADS} threads store+get"),
+        records.len() * 2,
+        start.elapsed(),
+    );
+}
+
+fn report(name: &str, operations: usize, elapsed: Duration) {
+    eprintln!(
+        "{:<36} {:>8} ops in {:>9.2?} ({:>10.0} ops/s)",
+        name,
+        operations,
+        elapsed,
+        operations as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,160 @@
+// Anti-entropy between a node and its closest neighbours. The records two nodes should both
+// hold are split into SYNC_RANGES ranges by the first bits of the key's id, and each side
+// summarizes a range with a digest over its (key, version) pairs. Only the key lists of the
+// ranges whose digests differ are exchanged, and only the records that are missing or
+// different on one side are sent:
+//
+//   A -> B  SyncDigest { ranges, .. }      A's digests, with a write token B gave A and one for
+//                                          B to write to A
+//   B -> A  SyncKeys { range, .. }         B's versions in a range that differs
+//   A -> B  Store ...                      what B is missing or holds another version of
+//   A -> B  SyncPull { keys }              what A is missing or holds another version of
+//   B -> A  Store ...
+//
+// A storage node keeps the newer of two versions, so sending both ways on a difference is enough.
+// B answers a peer's SyncDigest at most once every maintenance.sync_min_round_interval_secs, and
+// A only takes SyncKeys from peers it started a round with in that time.
+use crate::{
+    config::SYNC_RANGES,
+    contact::Contact,
+    node::Node,
+    record::Record,
+    sha::SHA,
+    storage::{Storage, StorageResult},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// a range's keys with the version each one is stored at
+pub type RangeEntries = BTreeMap<String, SHA>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RangeDigest {
+    pub range: u8,
+    pub count: u32,
+    pub digest: SHA,
+}
+
+// two copies of a record with the same signature are the same version
+pub fn fingerprint(record: &Record) -> SHA {
+    SHA::hash(&record.signature.0)
+}
+
+pub fn range_of(key: &str) -> u8 {
+    let bits = SYNC_RANGES.trailing_zeros();
+    SHA::hash(key.as_bytes()).0[0] >> (8 - bits)
+}
+
+// the signed records this node holds that, as far as it knows, the peer should hold too
+pub fn shared_entries<T: Storage>(
+    node: &Node<T>,
+    peer: &Contact,
+) -> StorageResult<BTreeMap<u8, RangeEntries>> {
+    let mut ranges: BTreeMap<u8, RangeEntries> = BTreeMap::new();
+    node.storage.for_each_entry(&mut |key, _, record| {
+        let Some(record) = record else {
+            return Ok(());
+        };
+        let key_id = SHA::hash(key.as_bytes());
+        let closest = node.routing_table.find_k_nearest_nodes(key_id);
+        if closest
+            .iter()
+            .take(record.replicas())
+            .any(|contact| contact.node_id == peer.node_id)
+        {
+            ranges
+                .entry(range_of(&key))
+                .or_default()
+                .insert(key, fingerprint(&record));
+        }
+        Ok(())
+    })?;
+    Ok(ranges)
+}
+
+// one digest for every range, empty ones included so a peer holding nothing still compares
+pub fn digests(ranges: &BTreeMap<u8, RangeEntries>) -> Vec<RangeDigest> {
+    let empty = RangeEntries::new();
+    (0..SYNC_RANGES as u8)
+        .map(|range| {
+            let entries = ranges.get(&range).unwrap_or(&empty);
+            let mut bytes = Vec::new();
+            for (key, version) in entries {
+                bytes.extend_from_slice(key.as_bytes());
+                bytes.push(0);
+                bytes.extend_from_slice(&version.0);
+            }
+            RangeDigest {
+                range,
+                count: entries.len() as u32,
+                digest: SHA::hash(&bytes),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &str)]) -> BTreeMap<u8, RangeEntries> {
+        let mut ranges: BTreeMap<u8, RangeEntries> = BTreeMap::new();
+        for (key, version) in pairs {
+            ranges
+                .entry(range_of(key))
+                .or_default()
+                .insert(key.to_string(), SHA::hash(version.as_bytes()));
+        }
+        ranges
+    }
+
+    #[test]
+    fn keys_fall_in_the_ranges() {
+        let mut seen = [false; SYNC_RANGES];
+        for index in 0..1000 {
+            let range = range_of(&format!("key{}", index)) as usize;
+            assert!(range < SYNC_RANGES);
+            seen[range] = true;
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    fn every_range_gets_a_digest() {
+        let digests = digests(&BTreeMap::new());
+        assert_eq!(digests.len(), SYNC_RANGES);
+        assert!(digests.iter().all(|digest| digest.count == 0));
+        let ranges: Vec<u8> = digests.iter().map(|digest| digest.range).collect();
+        assert_eq!(ranges, (0..SYNC_RANGES as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn digests_only_differ_where_the_entries_do() {
+        let ours = digests(&entries(&[("a", "1"), ("b", "1")]));
+        assert_eq!(ours, digests(&entries(&[("b", "1"), ("a", "1")])));
+
+        let theirs = digests(&entries(&[("a", "2"), ("b", "1")]));
+        let differing: Vec<u8> = ours
+            .iter()
+            .zip(&theirs)
+            .filter(|(ours, theirs)| ours != theirs)
+            .map(|(ours, _)| ours.range)
+            .collect();
+        assert_eq!(differing, [range_of("a")]);
+
+        let missing = digests(&entries(&[("a", "1")]));
+        assert_ne!(
+            ours[range_of("b") as usize],
+            missing[range_of("b") as usize]
+        );
+    }
+
+    #[test]
+    fn fingerprints_follow_the_signature() {
+        let identity = crate::identity::Identity::generate();
+        let record = Record::new(&identity, "key".to_string(), "a".to_string());
+        assert_eq!(fingerprint(&record), fingerprint(&record.clone()));
+        let other = Record::new(&identity, "key".to_string(), "b".to_string());
+        assert_ne!(fingerprint(&record), fingerprint(&other));
+    }
+}
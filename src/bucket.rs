@@ -1,4 +1,4 @@
-use crate::config::K;
+use crate::config;
 use crate::contact::Contact;
 use crate::sha::SHA;
 use std::collections::VecDeque;
@@ -14,7 +14,7 @@ impl KBucket {
     pub fn new(i: usize) -> Self {
         Self {
             i,
-            capacity: K,
+            capacity: config::get().protocol.k,
             nodes: VecDeque::new(),
         }
     }
@@ -0,0 +1,354 @@
+// Files too big for one record are split into FILE_CHUNK_SIZE chunks, each stored as an
+// immutable record under its own hash, and a manifest listing the chunk hashes is stored as an
+// immutable record too. The manifest's hash is all a reader needs: the manifest is checked
+// against it, and every chunk against the hash the manifest lists for it.
+//
+// Both directions resume: an upload remembers the chunks the network confirmed in the node's
+// data directory, a download writes into <output>.part and keeps the chunks that check out.
+use crate::{
+    config::{self, FILE_CHUNK_SIZE, FILE_MAX_CHUNKS},
+    logInfo, logWarn,
+    node::Node,
+    record::Record,
+    sha::SHA,
+    storage::Storage,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// an encoded manifest starts with these bytes and a format version, then the bincode manifest
+const MANIFEST_MAGIC: &[u8; 4] = b"KADM";
+const MANIFEST_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    // the file name it was uploaded from, used when the reader doesn't pick one
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: u32,
+    pub chunks: Vec<SHA>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.push(MANIFEST_VERSION);
+        bytes.extend(bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap());
+        bytes
+    }
+
+    // None if the bytes aren't a manifest, or one whose chunks don't add up to its size. Manifests
+    // come from the network, so only the sizes put_file writes are accepted: a reader allocates
+    // a chunk and sizes the file from them
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let body = bytes.strip_prefix(MANIFEST_MAGIC)?;
+        let (&version, body) = body.split_first()?;
+        if version != MANIFEST_VERSION {
+            return None;
+        }
+        let (manifest, _): (Self, _) =
+            bincode::serde::decode_from_slice(body, bincode::config::standard()).ok()?;
+        if manifest.chunk_size as usize != FILE_CHUNK_SIZE
+            || manifest.size > (FILE_MAX_CHUNKS * FILE_CHUNK_SIZE) as u64
+        {
+            return None;
+        }
+        let expected_chunks = manifest
+            .size
+            .checked_div(manifest.chunk_size as u64)?
+            .checked_add(!manifest.size.is_multiple_of(manifest.chunk_size as u64) as u64)?;
+        (manifest.chunks.len() as u64 == expected_chunks).then_some(manifest)
+    }
+
+    // where the chunk starts in the file and how long it is
+    fn chunk_span(&self, index: usize) -> (u64, usize) {
+        let offset = index as u64 * self.chunk_size as u64;
+        let length = (self.size - offset).min(self.chunk_size as u64) as usize;
+        (offset, length)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FileReport {
+    pub chunks: usize,
+    pub transferred: usize,
+    // chunks an earlier, interrupted transfer already took care of
+    pub resumed: usize,
+}
+
+impl fmt::Display for FileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks, {} transferred, {} resumed",
+            self.chunks, self.transferred, self.resumed
+        )
+    }
+}
+
+// stores the file's chunks and then its manifest, returns the manifest's hash. The node is only
+// locked for one chunk at a time, so it keeps answering its peers during a long upload
+pub fn put_file<T: Storage>(node: &Arc<Mutex<Node<T>>>, path: &Path) -> Result<(SHA, FileReport)> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let chunk_count = size.div_ceil(FILE_CHUNK_SIZE as u64) as usize;
+    if chunk_count > FILE_MAX_CHUNKS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} is too big, at most {} bytes can be stored",
+                path.display(),
+                FILE_MAX_CHUNKS * FILE_CHUNK_SIZE
+            ),
+        ));
+    }
+
+    let mut manifest = Manifest {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size,
+        chunk_size: FILE_CHUNK_SIZE as u32,
+        chunks: Vec::with_capacity(chunk_count),
+    };
+    for index in 0..chunk_count {
+        manifest
+            .chunks
+            .push(SHA::hash(&read_chunk(&mut file, &manifest, index)?));
+    }
+    let manifest_bytes = manifest.encode();
+    let manifest_hash = SHA::hash(&manifest_bytes);
+
+    let progress_path = node.lock().unwrap().data_dir.upload_path(&manifest_hash);
+    let mut stored: BTreeSet<SHA> = fs::read_to_string(&progress_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(SHA::from_hex)
+        .collect();
+    if let Some(parent) = progress_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut progress = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&progress_path)?;
+
+    let mut report = FileReport {
+        chunks: chunk_count,
+        ..FileReport::default()
+    };
+    for (index, chunk_hash) in manifest.chunks.iter().enumerate() {
+        if stored.contains(chunk_hash) {
+            report.resumed += 1;
+            continue;
+        }
+        let chunk = read_chunk(&mut file, &manifest, index)?;
+        let (_, store_report) = node.lock().unwrap().put_immutable(&chunk)?;
+        if store_report.replicas() == 0 {
+            return Err(Error::other(format!(
+                "no node confirmed chunk {} of {}, put_file again to resume",
+                index, chunk_count
+            )));
+        }
+        writeln!(progress, "{}", chunk_hash.to_hex())?;
+        stored.insert(*chunk_hash);
+        report.transferred += 1;
+    }
+
+    let (_, store_report) = node.lock().unwrap().put_immutable(&manifest_bytes)?;
+    if store_report.replicas() == 0 {
+        return Err(Error::other(
+            "no node confirmed the manifest, put_file again to resume",
+        ));
+    }
+    drop(progress);
+    fs::remove_file(&progress_path)?;
+    Ok((manifest_hash, report))
+}
+
+// fetches the manifest, then its chunks files.parallel_fetches at a time into <output>.part,
+// which is renamed to output once every chunk checked out. Without an output the file is
+// written to the current directory under the name it was uploaded with
+pub fn get_file<T: Storage>(
+    node: &Arc<Mutex<Node<T>>>,
+    manifest_hash: &SHA,
+    output: Option<&Path>,
+) -> Result<(PathBuf, FileReport)> {
+    let manifest_bytes = node
+        .lock()
+        .unwrap()
+        .get_immutable(manifest_hash)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no manifest found for {}", manifest_hash.to_hex()),
+            )
+        })?;
+    let manifest = Manifest::decode(&manifest_bytes).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a file manifest", manifest_hash.to_hex()),
+        )
+    })?;
+
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        // the name comes from whoever uploaded the file, only its last component is used
+        None => {
+            let output = Path::new(&manifest.name)
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(manifest_hash.to_hex()));
+            if output.exists() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists, pick an output", output.display()),
+                ));
+            }
+            output
+        }
+    };
+    let mut part_path = output.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+    let mut part = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&part_path)?;
+    part.set_len(manifest.size)?;
+
+    let mut report = FileReport {
+        chunks: manifest.chunks.len(),
+        ..FileReport::default()
+    };
+    let mut missing = Vec::new();
+    for (index, chunk_hash) in manifest.chunks.iter().enumerate() {
+        if SHA::hash(&read_chunk(&mut part, &manifest, index)?) == *chunk_hash {
+            report.resumed += 1;
+        } else {
+            missing.push(index);
+        }
+    }
+    if report.resumed > 0 {
+        logInfo!(
+            "Resuming {}: {} of {} chunks already fetched",
+            part_path.display(),
+            report.resumed,
+            report.chunks
+        );
+    }
+
+    let mut failed = 0;
+    for batch in missing.chunks(config::get().files.parallel_fetches) {
+        let keys = batch
+            .iter()
+            .map(|&index| Record::immutable_key(&manifest.chunks[index]))
+            .collect();
+        let records = node.lock().unwrap().get_records(keys);
+        for (&index, record) in batch.iter().zip(records) {
+            let (offset, length) = manifest.chunk_span(index);
+            let chunk = record.and_then(|record| record.content()).filter(|chunk| {
+                chunk.len() == length && SHA::hash(chunk) == manifest.chunks[index]
+            });
+            let Some(chunk) = chunk else {
+                logWarn!("Couldn't fetch chunk {} of {}", index, report.chunks);
+                failed += 1;
+                continue;
+            };
+            part.seek(SeekFrom::Start(offset))?;
+            part.write_all(&chunk)?;
+            report.transferred += 1;
+        }
+    }
+    part.sync_all()?;
+    if failed > 0 {
+        return Err(Error::other(format!(
+            "{} of {} chunks couldn't be fetched, get_file again to resume",
+            failed, report.chunks
+        )));
+    }
+    drop(part);
+    fs::rename(&part_path, &output)?;
+    Ok((output, report))
+}
+
+fn read_chunk(file: &mut File, manifest: &Manifest, index: usize) -> Result<Vec<u8>> {
+    let (offset, length) = manifest.chunk_span(index);
+    let mut chunk = vec![0u8; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut chunk)?;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(size: u64) -> Manifest {
+        let chunks = size.div_ceil(FILE_CHUNK_SIZE as u64);
+        Manifest {
+            name: "file".to_string(),
+            size,
+            chunk_size: FILE_CHUNK_SIZE as u32,
+            chunks: (0..chunks)
+                .map(|index| SHA::hash(&index.to_be_bytes()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        for size in [1, FILE_CHUNK_SIZE as u64, FILE_CHUNK_SIZE as u64 * 3 + 1] {
+            let manifest = manifest(size);
+            assert_eq!(Manifest::decode(&manifest.encode()), Some(manifest));
+        }
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        let manifest = manifest(0);
+        assert!(manifest.chunks.is_empty());
+        assert_eq!(Manifest::decode(&manifest.encode()), Some(manifest));
+    }
+
+    #[test]
+    fn chunk_spans_cover_the_file() {
+        let manifest = manifest(FILE_CHUNK_SIZE as u64 * 2 + 10);
+        assert_eq!(manifest.chunk_span(0), (0, FILE_CHUNK_SIZE));
+        assert_eq!(manifest.chunk_span(2), (FILE_CHUNK_SIZE as u64 * 2, 10));
+    }
+
+    #[test]
+    fn decode_rejects_what_put_file_never_writes() {
+        let mut wrong_count = manifest(FILE_CHUNK_SIZE as u64 + 1);
+        wrong_count.chunks.pop();
+        assert_eq!(Manifest::decode(&wrong_count.encode()), None);
+
+        let mut wrong_chunk_size = manifest(10);
+        wrong_chunk_size.chunk_size = 10;
+        assert_eq!(Manifest::decode(&wrong_chunk_size.encode()), None);
+
+        let mut too_big = manifest(0);
+        too_big.size = (FILE_MAX_CHUNKS * FILE_CHUNK_SIZE) as u64 + 1;
+        too_big.chunk_size = u32::MAX;
+        assert_eq!(Manifest::decode(&too_big.encode()), None);
+    }
+
+    #[test]
+    fn decode_rejects_other_bytes() {
+        let mut bytes = manifest(1).encode();
+        assert_eq!(Manifest::decode(&bytes[..bytes.len() - 1]), None);
+        bytes[MANIFEST_MAGIC.len()] = MANIFEST_VERSION + 1;
+        assert_eq!(Manifest::decode(&bytes), None);
+        assert_eq!(Manifest::decode(b""), None);
+        assert_eq!(Manifest::decode(b"not a manifest"), None);
+    }
+}
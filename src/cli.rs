@@ -1,25 +1,217 @@
+use crate::{
+    config::{LOG_FILE_MAX_BYTES, LOG_FILES_KEPT},
+    logging::Format,
+};
 use clap::*;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Parser)]
 #[clap(name = "kade")]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    // a TOML file with the protocol and runtime parameters, see config.rs.
+    // Defaults to the path in KADE_CONFIG
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    // overrides one parameter, e.g. --set protocol.k=16, can be repeated
+    #[clap(long = "set", global = true, value_name = "SECTION.FIELD=VALUE")]
+    pub overrides: Vec<String>,
+
+    // which messages to log, e.g. info,kademlia::network=trace, see logging.rs.
+    // Defaults to KADE_LOG, then info
+    #[clap(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    // text or json
+    #[clap(long, global = true, default_value = "text")]
+    pub log_format: Format,
+
+    // write the logs to this file instead of stdout, rotating it as it grows
+    #[clap(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    #[clap(long, global = true, default_value_t = LOG_FILE_MAX_BYTES)]
+    pub log_file_max_bytes: u64,
+
+    // how many rotated log files to keep
+    #[clap(long, global = true, default_value_t = LOG_FILES_KEPT)]
+    pub log_files: usize,
 }
 
 #[derive(Subcommand, Clone, Debug)]
 pub enum Commands {
-    Init {
-        #[clap(short, long)]
-        name: String,
-
-        #[clap(short, long)]
-        port: Option<u16>,
-
+    // runs a node and reads commands from stdin
+    Init(InitArgs),
+    // runs a node without a terminal, controlled through a Unix socket instead of stdin,
+    // see control.rs
+    Daemon {
+        #[command(flatten)]
+        node: InitArgs,
+        // defaults to kade.sock in the node's data directory
         #[clap(long)]
-        bootstrap_ip: Option<String>,
-
+        socket: Option<PathBuf>,
+    },
+    // sends one JSON-RPC request to a daemon and prints the result,
+    // e.g. kade ctl get '{"key": "a"}' --name a
+    Ctl {
+        method: String,
+        // the request's params as a JSON object
+        params: Option<String>,
+        #[clap(long)]
+        socket: Option<PathBuf>,
         #[clap(long)]
-        bootstrap_port: Option<u16>,
+        name: Option<String>,
+        #[clap(long)]
+        data_dir: Option<PathBuf>,
+    },
+    // prints the effective configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    // the others do one thing and exit, non-zero if it failed (2 if what was asked for
+    // doesn't exist), see client.rs
+    Put {
+        key: String,
+        value: String,
+        // fail unless at least this many nodes confirmed the store
+        #[clap(long, default_value_t = 1)]
+        min_replicas: usize,
+        // store the value as Reed–Solomon shards instead of full copies
+        #[clap(long)]
+        coded: bool,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    Get {
+        key: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    // a get that prints every node the lookup asked, what they answered and why it stopped
+    TraceGet {
+        key: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    Delete {
+        key: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    // ip:port of the node to ping
+    Ping {
+        address: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    // the k closest nodes to a 40 hex character id
+    FindNode {
+        id: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    Stats {
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    PutFile {
+        path: PathBuf,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    GetFile {
+        hash: String,
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        client: ClientArgs,
     },
 }
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ConfigCommand {
+    Show,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct InitArgs {
+    #[clap(short, long)]
+    pub name: String,
+
+    #[clap(short, long)]
+    pub port: Option<u16>,
+
+    #[clap(long)]
+    pub bootstrap_ip: Option<String>,
+
+    #[clap(long)]
+    pub bootstrap_port: Option<u16>,
+
+    #[clap(long, value_enum, default_value_t = StorageBackend::Sqlite)]
+    pub storage: StorageBackend,
+
+    // where the node keeps its metadata, database and routing table,
+    // defaults to kade_data/<name>
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+
+    // serve the HTTP gateway on this ip:port, needs the binary to be built with the `http`
+    // feature, see gateway.rs
+    #[clap(long)]
+    pub http: Option<String>,
+
+    // serve only the metrics, on ip:port/metrics, also needs the `http` feature. The gateway
+    // serves them too
+    #[clap(long)]
+    pub metrics: Option<String>,
+
+    // hand the stored records over to the closest neighbours before shutting down, see
+    // runtime.rs
+    #[clap(long)]
+    pub hand_off_on_shutdown: bool,
+}
+
+// how a one-shot command reaches the network: through a temporary node that joins via the
+// bootstrap node, or that runs as an existing (stopped) node from its data directory, with
+// its identity, storage and known contacts
+#[derive(Args, Clone, Debug)]
+pub struct ClientArgs {
+    #[clap(long)]
+    pub name: Option<String>,
+
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = StorageBackend::Sqlite)]
+    pub storage: StorageBackend,
+
+    #[clap(long)]
+    pub bootstrap_ip: Option<String>,
+
+    #[clap(long)]
+    pub bootstrap_port: Option<u16>,
+
+    // 0 lets the OS pick one
+    #[clap(long, default_value_t = 0)]
+    pub port: u16,
+
+    // print the result as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    // show the node's logs on stderr
+    #[clap(long)]
+    pub verbose: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Sqlite,
+    // nothing is persisted, for tests and ephemeral nodes
+    Memory,
+    // needs the binary to be built with the `sled` feature
+    Sled,
+}
@@ -0,0 +1,262 @@
+// One-shot commands (kade put, get, ping, ...): a temporary node joins through the bootstrap
+// node, or runs as an existing node from its data directory, does one thing and exits.
+// The result goes to stdout, as text or with --json as JSON, errors go to stderr (or stdout as
+// {"error": ...} with --json), and the exit code is 0 if it worked, 2 if what was asked for
+// doesn't exist and 1 for any other failure. A command for a node that is running as a daemon
+// is sent to it through its control socket (see control.rs), like kade ctl does
+use crate::{
+    cli::{ClientArgs, Commands, StorageBackend},
+    config::{self, SOCKET_FILE},
+    control::{self, Outcome, Request},
+    data_dir::DataDir,
+    logging::{self, LogOutput},
+    memory_storage::MemoryStorage,
+    node::Node,
+    node_metadata::MetaData,
+    runtime::Shutdown,
+    storage::{SqlLiteStorage, Storage, StorageResult},
+};
+use serde_json::json;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "sled")]
+use crate::sled_storage::SledStorage;
+
+pub fn run(command: &Commands) -> ExitCode {
+    let Some(args) = client_args(command) else {
+        return ExitCode::FAILURE;
+    };
+    logging::set_output(if args.verbose {
+        LogOutput::Stderr
+    } else {
+        LogOutput::Off
+    });
+
+    match execute_on_node(command, args) {
+        Ok(outcome) if args.json => println!("{}", outcome.json),
+        Ok(outcome) => println!("{}", outcome.text),
+        Err(e) => {
+            if args.json {
+                println!("{}", json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("error: {}", e);
+            }
+            return match e.kind() {
+                ErrorKind::NotFound => ExitCode::from(2),
+                _ => ExitCode::FAILURE,
+            };
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn client_args(command: &Commands) -> Option<&ClientArgs> {
+    match command {
+        Commands::Init(_)
+        | Commands::Daemon { .. }
+        | Commands::Ctl { .. }
+        | Commands::Config { .. } => None,
+        Commands::Put { client, .. }
+        | Commands::Get { client, .. }
+        | Commands::TraceGet { client, .. }
+        | Commands::Delete { client, .. }
+        | Commands::Ping { client, .. }
+        | Commands::FindNode { client, .. }
+        | Commands::Stats { client }
+        | Commands::PutFile { client, .. }
+        | Commands::GetFile { client, .. } => Some(client),
+    }
+}
+
+// runs the command on a throwaway node with nothing stored, or on the node given by --name or
+// --data-dir: through its control socket if it runs as a daemon, otherwise as that node
+fn execute_on_node(command: &Commands, args: &ClientArgs) -> Result<Outcome> {
+    let root = match (&args.name, &args.data_dir) {
+        (_, Some(data_dir)) => Some(data_dir.clone()),
+        (Some(name), None) => Some(DataDir::root_for(name, None)),
+        (None, None) => None,
+    };
+    let Some(root) = root else {
+        let data_dir = DataDir::temporary()?;
+        let root = data_dir.root.clone();
+        let node = Node::from_metadata(
+            MetaData::temporary(args.port),
+            data_dir,
+            MemoryStorage::new(),
+            true,
+        );
+        let outcome = node.and_then(|node| execute(command, args, node));
+        let _ = fs::remove_dir_all(root);
+        return outcome;
+    };
+
+    // a running node holds the lock on its data directory, it answers through its socket
+    let socket = root.join(SOCKET_FILE);
+    if socket.exists() {
+        match control::call_request(&socket, &request(command)) {
+            // a socket left behind by a node that crashed
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+            outcome => return outcome,
+        }
+    }
+
+    let metadata_path = root.join("metadata.json");
+    if !metadata_path.exists() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no node in {}, kade init creates one", root.display()),
+        ));
+    }
+    let data_dir = DataDir::open(root)?;
+    let mut metadata = MetaData::load(&metadata_path)?;
+    metadata.port = args.port;
+    if args.bootstrap_ip.is_some() {
+        metadata.bootstrap_ip = None;
+        metadata.bootstrap_port = None;
+    }
+    match args.storage {
+        StorageBackend::Sqlite => {
+            let storage = storage(SqlLiteStorage::new(data_dir.sqlite_path()))?;
+            execute(
+                command,
+                args,
+                Node::from_metadata(metadata, data_dir, storage, true)?,
+            )
+        }
+        StorageBackend::Memory => {
+            let node = Node::from_metadata(metadata, data_dir, MemoryStorage::new(), true)?;
+            execute(command, args, node)
+        }
+        #[cfg(feature = "sled")]
+        StorageBackend::Sled => {
+            let storage = storage(SledStorage::new(data_dir.sled_path()))?;
+            execute(
+                command,
+                args,
+                Node::from_metadata(metadata, data_dir, storage, true)?,
+            )
+        }
+        #[cfg(not(feature = "sled"))]
+        StorageBackend::Sled => Err(Error::new(
+            ErrorKind::Unsupported,
+            "this binary was built without the sled feature",
+        )),
+    }
+}
+
+fn storage<T>(storage: StorageResult<T>) -> Result<T> {
+    storage.map_err(|e| Error::other(format!("failed to open the database: {}", e.message)))
+}
+
+fn execute<T: Storage + Send + 'static>(
+    command: &Commands,
+    args: &ClientArgs,
+    node: Node<T>,
+) -> Result<Outcome> {
+    let node = Arc::new(Mutex::new(node));
+    Node::spawn_listener(&node, Arc::new(AtomicBool::new(false)));
+
+    if let (Some(ip), Some(port)) = (&args.bootstrap_ip, args.bootstrap_port) {
+        let address = control::socket_address(&format!("{}:{}", ip, port))?;
+        let mut node = node.lock().unwrap();
+        match node.ping(address, config::response_timeout())? {
+            Some(contact) => {
+                node.routing_table.insert_node(&contact);
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("the bootstrap node at {} didn't answer", address),
+                ));
+            }
+        }
+    }
+
+    let outcome = control::handle(&node, request(command), &Shutdown::default())?;
+
+    // a node run from its data directory remembers who it met, like it would running for good
+    if args.name.is_some() || args.data_dir.is_some() {
+        node.lock().unwrap().save_routing_table()?;
+    }
+    Ok(outcome)
+}
+
+// the request a one-shot command makes
+fn request(command: &Commands) -> Request {
+    match command.clone() {
+        Commands::Put {
+            key,
+            value,
+            min_replicas,
+            coded,
+            ..
+        } => Request::Store {
+            key,
+            value,
+            min_replicas,
+            coded,
+        },
+        Commands::Get { key, .. } => Request::Get { key },
+        Commands::TraceGet { key, .. } => Request::TraceGet { key },
+        Commands::Delete { key, .. } => Request::Delete { key },
+        Commands::Ping { address, .. } => Request::Ping { address },
+        Commands::FindNode { id, .. } => Request::FindNode { id },
+        Commands::Stats { .. } => Request::Stats {},
+        Commands::PutFile { path, .. } => Request::PutFile { path },
+        Commands::GetFile { hash, output, .. } => Request::GetFile { hash, output },
+        Commands::Init(_)
+        | Commands::Daemon { .. }
+        | Commands::Ctl { .. }
+        | Commands::Config { .. } => {
+            unreachable!("only one-shot commands make requests")
+        }
+    }
+}
+
+// kade ctl: sends one request to a running daemon and prints its result as JSON, with the
+// same exit codes as the one-shot commands
+pub fn ctl(command: &Commands) -> ExitCode {
+    let Commands::Ctl {
+        method,
+        params,
+        socket,
+        name,
+        data_dir,
+    } = command
+    else {
+        return ExitCode::FAILURE;
+    };
+    let socket = match (socket, name, data_dir) {
+        (Some(socket), _, _) => socket.clone(),
+        (None, _, Some(data_dir)) => data_dir.join(SOCKET_FILE),
+        (None, Some(name), None) => DataDir::root_for(name, None).join(SOCKET_FILE),
+        (None, None, None) => {
+            eprintln!("error: pick the daemon with --socket, --name or --data-dir");
+            return ExitCode::FAILURE;
+        }
+    };
+    let params = match params.as_deref().map(serde_json::from_str).transpose() {
+        Ok(params) => params.unwrap_or_else(|| json!({})),
+        Err(e) => {
+            eprintln!("error: params aren't valid JSON: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match control::call(&socket, method, params) {
+        Ok(result) => {
+            println!("{}", result);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            match e.kind() {
+                ErrorKind::NotFound => ExitCode::from(2),
+                _ => ExitCode::FAILURE,
+            }
+        }
+    }
+}
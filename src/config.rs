@@ -1,3 +1,544 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
 pub const K: usize = 20;
 pub const ALPHA: usize = 3;
 pub const ID_BITS: usize = 160;
+// how long a tombstone hides a deleted key, and how often its holders republish it
+pub const TOMBSTONE_TTL_SECS: i64 = 24 * 60 * 60;
+pub const REPUBLISH_INTERVAL_SECS: u64 = 60 * 60;
+// how long a storage node remembers that a peer provides a key, and how many it remembers
+pub const PROVIDER_TTL_SECS: i64 = 24 * 60 * 60;
+pub const MAX_PROVIDERS_PER_KEY: usize = K;
+// write tokens are issued from a secret that rotates this often,
+// a token stays valid until the end of the next period
+pub const TOKEN_ROTATION_SECS: u64 = 5 * 60;
+// nodes started without --data-dir keep their files in <DEFAULT_DATA_ROOT>/<name>
+pub const DEFAULT_DATA_ROOT: &str = "kade_data";
+// the control socket a daemon listens on, in its data directory unless --socket moves it
+pub const SOCKET_FILE: &str = "kade.sock";
+// a log file given with --log-file is rotated once it reaches this size, and this many of the
+// rotated files are kept
+pub const LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const LOG_FILES_KEPT: usize = 5;
+// how often the routing table is written to the data directory
+pub const ROUTING_TABLE_SNAPSHOT_SECS: u64 = 60;
+// how many pairs the list command loads from storage at a time
+pub const LIST_PAGE_SIZE: usize = 100;
+// the biggest value the HTTP gateway accepts in a PUT, a record has to fit in one datagram
+pub const HTTP_MAX_BODY_BYTES: usize = 48 * 1024;
+// how often stored records are checked for damage
+pub const VERIFY_INTERVAL_SECS: u64 = 24 * 60 * 60;
+// limits on handing records over to a node that joined close to them: at most
+// HANDOFF_MAX_RECORDS per node, sent HANDOFF_BATCH_SIZE at a time every HANDOFF_BATCH_INTERVAL_MS,
+// and at most HANDOFF_MAX_PENDING nodes waiting for theirs
+pub const HANDOFF_MAX_RECORDS: usize = 1000;
+pub const HANDOFF_BATCH_SIZE: usize = 20;
+pub const HANDOFF_BATCH_INTERVAL_MS: u64 = 200;
+pub const HANDOFF_MAX_PENDING: usize = 64;
+// anti-entropy: how often a node compares its records with its SYNC_PEERS closest neighbours,
+// over how many key ranges (a power of two, at most 256), and how much goes in one round
+pub const SYNC_INTERVAL_SECS: u64 = 10 * 60;
+pub const SYNC_PEERS: usize = 3;
+pub const SYNC_RANGES: usize = 16;
+pub const SYNC_MAX_KEYS_PER_MESSAGE: usize = 200;
+pub const SYNC_MAX_TRANSFERS: usize = 100;
+// a peer may start an anti-entropy round with us at most this often
+pub const SYNC_MIN_ROUND_INTERVAL_SECS: u64 = 60;
+// files are stored as chunks of FILE_CHUNK_SIZE bytes, small enough for a chunk record to fit
+// in one datagram, listed by a manifest record that has room for FILE_MAX_CHUNKS of them.
+// A download looks up FILE_PARALLEL_FETCHES chunks at once
+pub const FILE_CHUNK_SIZE: usize = 32 * 1024;
+pub const FILE_MAX_CHUNKS: usize = 1500;
+pub const FILE_PARALLEL_FETCHES: usize = 8;
+// erasure coded values are split into ERASURE_DATA_SHARDS shards plus ERASURE_PARITY_SHARDS
+// parity shards unless the publisher picks otherwise, at most ERASURE_MAX_SHARDS in all, and
+// every shard is stored on the ERASURE_SHARD_REPLICAS nodes closest to its key
+pub const ERASURE_DATA_SHARDS: usize = 4;
+pub const ERASURE_PARITY_SHARDS: usize = 2;
+pub const ERASURE_MAX_SHARDS: usize = 32;
+pub const ERASURE_SHARD_REPLICAS: usize = 2;
+// the receive buffer holds the largest UDP payload by default, provider responses carry two
+// lists of contacts
+pub const RECEIVE_BUFFER_BYTES: usize = 65507;
+// how long a request waits for its answer, how many rounds an iterative lookup runs and how
+// often waiting loops look for what arrived
+pub const RESPONSE_TIMEOUT_MS: u64 = 2000;
+pub const LOOKUP_ITERATIONS: usize = 3;
+pub const POLL_INTERVAL_MS: u64 = 100;
+
+// The constants above are the defaults of the parameters a node can be configured with. The
+// effective configuration is built once at startup: the defaults, then a TOML file (--config,
+// or the path in KADE_CONFIG), then KADE_<SECTION>_<FIELD> environment variables, then
+// --set section.field=value on the command line, and it is validated before any node starts.
+// ID_BITS, SYNC_RANGES and the sizes bounded by what fits in a datagram stay constants, every
+// node has to agree on them
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub protocol: ProtocolConfig,
+    pub network: NetworkConfig,
+    pub records: RecordsConfig,
+    pub maintenance: MaintenanceConfig,
+    pub files: FilesConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    pub k: usize,
+    pub alpha: usize,
+    pub response_timeout_ms: u64,
+    pub lookup_iterations: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub receive_buffer_bytes: usize,
+    pub poll_interval_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordsConfig {
+    pub tombstone_ttl_secs: i64,
+    pub provider_ttl_secs: i64,
+    pub max_providers_per_key: usize,
+    pub token_rotation_secs: u64,
+    pub erasure_data_shards: usize,
+    pub erasure_parity_shards: usize,
+    pub erasure_shard_replicas: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub republish_interval_secs: u64,
+    pub routing_table_snapshot_secs: u64,
+    pub verify_interval_secs: u64,
+    pub sync_interval_secs: u64,
+    pub sync_peers: usize,
+    pub sync_max_keys_per_message: usize,
+    pub sync_max_transfers: usize,
+    pub sync_min_round_interval_secs: u64,
+    pub handoff_max_records: usize,
+    pub handoff_batch_size: usize,
+    pub handoff_batch_interval_ms: u64,
+    pub handoff_max_pending: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub parallel_fetches: usize,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            k: K,
+            alpha: ALPHA,
+            response_timeout_ms: RESPONSE_TIMEOUT_MS,
+            lookup_iterations: LOOKUP_ITERATIONS,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            receive_buffer_bytes: RECEIVE_BUFFER_BYTES,
+            poll_interval_ms: POLL_INTERVAL_MS,
+        }
+    }
+}
+
+impl Default for RecordsConfig {
+    fn default() -> Self {
+        Self {
+            tombstone_ttl_secs: TOMBSTONE_TTL_SECS,
+            provider_ttl_secs: PROVIDER_TTL_SECS,
+            max_providers_per_key: MAX_PROVIDERS_PER_KEY,
+            token_rotation_secs: TOKEN_ROTATION_SECS,
+            erasure_data_shards: ERASURE_DATA_SHARDS,
+            erasure_parity_shards: ERASURE_PARITY_SHARDS,
+            erasure_shard_replicas: ERASURE_SHARD_REPLICAS,
+        }
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            republish_interval_secs: REPUBLISH_INTERVAL_SECS,
+            routing_table_snapshot_secs: ROUTING_TABLE_SNAPSHOT_SECS,
+            verify_interval_secs: VERIFY_INTERVAL_SECS,
+            sync_interval_secs: SYNC_INTERVAL_SECS,
+            sync_peers: SYNC_PEERS,
+            sync_max_keys_per_message: SYNC_MAX_KEYS_PER_MESSAGE,
+            sync_max_transfers: SYNC_MAX_TRANSFERS,
+            sync_min_round_interval_secs: SYNC_MIN_ROUND_INTERVAL_SECS,
+            handoff_max_records: HANDOFF_MAX_RECORDS,
+            handoff_batch_size: HANDOFF_BATCH_SIZE,
+            handoff_batch_interval_ms: HANDOFF_BATCH_INTERVAL_MS,
+            handoff_max_pending: HANDOFF_MAX_PENDING,
+        }
+    }
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            parallel_fetches: FILE_PARALLEL_FETCHES,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    pub fn response_timeout(&self) -> Duration {
+        Duration::from_millis(self.response_timeout_ms)
+    }
+}
+
+impl NetworkConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// the effective configuration, the defaults unless one was installed before the first call
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+// the two parameters most of the waiting loops need
+pub fn response_timeout() -> Duration {
+    get().protocol.response_timeout()
+}
+
+pub fn poll_interval() -> Duration {
+    get().network.poll_interval()
+}
+
+// makes config the one every node in the process uses, which only works before anything read
+// it, so embedding applications call it before creating their first node
+pub fn install(config: Config) -> Result<()> {
+    CONFIG.set(config).map_err(|_| {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            "the configuration is already in use",
+        )
+    })
+}
+
+impl Config {
+    // the defaults, then the file, the environment and the overrides, validated
+    pub fn load(file: Option<&Path>, overrides: &[String]) -> Result<Self> {
+        let file = file
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os("KADE_CONFIG").map(PathBuf::from));
+        let mut table = match &file {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("failed to read {}: {}", path.display(), e),
+                    )
+                })?;
+                text.parse::<toml::Table>().map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} isn't valid TOML: {}", path.display(), e),
+                    )
+                })?
+            }
+            None => toml::Table::new(),
+        };
+        // the file's values are checked against the known fields before anything is
+        // layered on top, so a misspelt one doesn't go unnoticed
+        if let Some(path) = &file {
+            Self::from_table(table.clone())
+                .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+
+        for (section, field) in Self::fields() {
+            let name = format!("KADE_{}_{}", section, field).to_uppercase();
+            if let Ok(value) = env::var(&name) {
+                Self::set(&mut table, &section, &field, &value)
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", name, e)))?;
+            }
+        }
+        for assignment in overrides {
+            let parsed = assignment
+                .split_once('=')
+                .and_then(|(path, value)| Some((path.split_once('.')?, value)));
+            let Some(((section, field), value)) = parsed else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "invalid override {}, expected section.field=value",
+                        assignment
+                    ),
+                ));
+            };
+            Self::set(&mut table, section, field, value).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("{}: {}", assignment, e))
+            })?;
+        }
+
+        let config = Self::from_table(table)?;
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid configuration: {}", problems.join("; ")),
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    fn from_table(table: toml::Table) -> Result<Self> {
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    // every (section, field) there is, as named in the file
+    fn fields() -> Vec<(String, String)> {
+        let toml::Value::Table(sections) = toml::Value::try_from(Self::default()).unwrap() else {
+            unreachable!("the configuration serializes to a table");
+        };
+        sections
+            .into_iter()
+            .flat_map(|(section, fields)| {
+                let fields = fields.as_table().cloned().unwrap_or_default();
+                fields
+                    .into_iter()
+                    .map(move |(field, _)| (section.clone(), field))
+            })
+            .collect()
+    }
+
+    // sets one field from its text, every parameter is an integer
+    fn set(table: &mut toml::Table, section: &str, field: &str, value: &str) -> Result<()> {
+        let known = Self::fields()
+            .iter()
+            .any(|(known_section, known_field)| known_section == section && known_field == field);
+        if !known {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown setting {}.{}", section, field),
+            ));
+        }
+        let value = value.parse::<i64>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't an integer", value),
+            )
+        })?;
+        let section = table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let toml::Value::Table(section) = section else {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a section"));
+        };
+        section.insert(field.to_string(), toml::Value::Integer(value));
+        Ok(())
+    }
+
+    // what's wrong with the values, empty if nothing is
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        let Config {
+            protocol,
+            network,
+            records,
+            maintenance,
+            files,
+        } = self;
+        check(protocol.k >= 1, "protocol.k must be at least 1");
+        check(
+            (1..=protocol.k).contains(&protocol.alpha),
+            "protocol.alpha must be between 1 and protocol.k",
+        );
+        check(
+            protocol.response_timeout_ms >= 1,
+            "protocol.response_timeout_ms must be at least 1",
+        );
+        check(
+            protocol.lookup_iterations >= 1,
+            "protocol.lookup_iterations must be at least 1",
+        );
+        check(
+            (1024..=RECEIVE_BUFFER_BYTES).contains(&network.receive_buffer_bytes),
+            "network.receive_buffer_bytes must be between 1024 and 65507",
+        );
+        check(
+            (1..=1000).contains(&network.poll_interval_ms),
+            "network.poll_interval_ms must be between 1 and 1000",
+        );
+        check(
+            records.tombstone_ttl_secs >= 1,
+            "records.tombstone_ttl_secs must be at least 1",
+        );
+        check(
+            records.provider_ttl_secs >= 1,
+            "records.provider_ttl_secs must be at least 1",
+        );
+        check(
+            records.max_providers_per_key >= 1,
+            "records.max_providers_per_key must be at least 1",
+        );
+        check(
+            records.token_rotation_secs >= 1,
+            "records.token_rotation_secs must be at least 1",
+        );
+        check(
+            records.erasure_data_shards >= 1
+                && records.erasure_parity_shards >= 1
+                && records.erasure_data_shards + records.erasure_parity_shards
+                    <= ERASURE_MAX_SHARDS,
+            "records.erasure_data_shards and records.erasure_parity_shards must be at least 1 and add up to at most 32",
+        );
+        check(
+            (1..=protocol.k).contains(&records.erasure_shard_replicas),
+            "records.erasure_shard_replicas must be between 1 and protocol.k",
+        );
+        for (value, name) in [
+            (
+                maintenance.republish_interval_secs,
+                "maintenance.republish_interval_secs",
+            ),
+            (
+                maintenance.routing_table_snapshot_secs,
+                "maintenance.routing_table_snapshot_secs",
+            ),
+            (
+                maintenance.verify_interval_secs,
+                "maintenance.verify_interval_secs",
+            ),
+            (
+                maintenance.sync_interval_secs,
+                "maintenance.sync_interval_secs",
+            ),
+            (
+                maintenance.sync_min_round_interval_secs,
+                "maintenance.sync_min_round_interval_secs",
+            ),
+        ] {
+            check(value >= 1, &format!("{} must be at least 1", name));
+        }
+        for (value, name) in [
+            (maintenance.sync_peers, "maintenance.sync_peers"),
+            (
+                maintenance.sync_max_keys_per_message,
+                "maintenance.sync_max_keys_per_message",
+            ),
+            (
+                maintenance.sync_max_transfers,
+                "maintenance.sync_max_transfers",
+            ),
+            (
+                maintenance.handoff_max_records,
+                "maintenance.handoff_max_records",
+            ),
+            (
+                maintenance.handoff_batch_size,
+                "maintenance.handoff_batch_size",
+            ),
+            (
+                maintenance.handoff_max_pending,
+                "maintenance.handoff_max_pending",
+            ),
+            (files.parallel_fetches, "files.parallel_fetches"),
+        ] {
+            check(value >= 1, &format!("{} must be at least 1", name));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(assignments: &[&str]) -> Result<Config> {
+        let assignments: Vec<String> = assignments.iter().map(|a| a.to_string()).collect();
+        Config::load(None, &assignments)
+    }
+
+    fn error(result: Result<Config>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().problems().is_empty());
+        let config = overrides(&[]).unwrap();
+        assert_eq!(config.protocol.k, K);
+        assert_eq!(config.to_toml(), Config::default().to_toml());
+    }
+
+    #[test]
+    fn overrides_set_fields() {
+        let config = overrides(&["protocol.k=8", "protocol.alpha=2", "protocol.k=10"]).unwrap();
+        // the last one wins
+        assert_eq!(config.protocol.k, 10);
+        assert_eq!(config.protocol.alpha, 2);
+        assert_eq!(config.network.poll_interval_ms, POLL_INTERVAL_MS);
+    }
+
+    #[test]
+    fn overrides_are_checked() {
+        assert!(error(overrides(&["protocol.k"])).contains("expected section.field=value"));
+        assert!(error(overrides(&["k=8"])).contains("expected section.field=value"));
+        assert!(error(overrides(&["protocol.kk=8"])).contains("unknown setting protocol.kk"));
+        assert!(error(overrides(&["nope.k=8"])).contains("unknown setting nope.k"));
+        assert!(error(overrides(&["protocol.k=eight"])).contains("eight isn't an integer"));
+        assert!(error(overrides(&["protocol.k=-1"])).contains("invalid value"));
+    }
+
+    #[test]
+    fn values_are_validated_together() {
+        let message = error(overrides(&["protocol.k=2", "protocol.alpha=3"]));
+        assert!(message.contains("protocol.alpha must be between 1 and protocol.k"));
+        let message = error(overrides(&["protocol.k=0", "network.poll_interval_ms=0"]));
+        assert!(message.contains("protocol.k must be at least 1"));
+        assert!(message.contains("network.poll_interval_ms must be between 1 and 1000"));
+    }
+
+    #[test]
+    fn file_values_are_checked_then_overridden() {
+        let dir = env::temp_dir().join(format!("kade-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kade.toml");
+
+        fs::write(&path, "[protocol]\nk = 12\nalpha = 4\n").unwrap();
+        let config = Config::load(Some(&path), &["protocol.alpha=5".to_string()]).unwrap();
+        assert_eq!(config.protocol.k, 12);
+        assert_eq!(config.protocol.alpha, 5);
+
+        fs::write(&path, "[protocol]\nkay = 12\n").unwrap();
+        assert!(error(Config::load(Some(&path), &[])).contains("unknown field"));
+        fs::write(&path, "[protocol\n").unwrap();
+        assert!(error(Config::load(Some(&path), &[])).contains("isn't valid TOML"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,589 @@
+// The requests a node answers on behalf of a user, and the control socket a daemon
+// (kade daemon) serves them on: newline-delimited JSON-RPC 2.0 over a Unix domain socket,
+// <data-dir>/kade.sock unless --socket says otherwise. Every line is one request,
+//
+//   {"jsonrpc": "2.0", "id": 1, "method": "get", "params": {"key": "a"}}
+//
+// answered by one line with the same id and either an error or a result,
+// {"data": ..., "text": ...} with the answer as JSON and the way the one-shot commands print it.
+// The one-shot commands in client.rs run the same requests on a node of their own.
+use crate::{
+    chunking,
+    config::{self, LIST_PAGE_SIZE},
+    contact::Contact,
+    logError, logInfo, logging,
+    node::Node,
+    runtime::Shutdown,
+    sha::SHA,
+    storage::{KeyRange, Storage},
+    store_report::StoreReport,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// the JSON-RPC error codes we answer with, -32000 and below are ours
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const FAILED: i64 = -32000;
+const NOT_FOUND: i64 = -32001;
+
+// the method is the variant's name in snake_case and params its fields, params can be left out
+// when every field is optional
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    Store {
+        key: String,
+        value: String,
+        #[serde(default = "one")]
+        min_replicas: usize,
+        // stores the value as Reed–Solomon shards with the default shard counts
+        #[serde(default)]
+        coded: bool,
+    },
+    Get {
+        key: String,
+    },
+    // a get that also shows how its lookup went, see lookup_trace.rs, not finding the value
+    // isn't an error here
+    TraceGet {
+        key: String,
+    },
+    Delete {
+        key: String,
+    },
+    // one page of what the node stores itself, pass next_cursor back as cursor for the next
+    List {
+        prefix: Option<String>,
+        start: Option<String>,
+        end: Option<String>,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    },
+    RoutingTable {},
+    Stats {},
+    Ping {
+        address: String,
+    },
+    FindNode {
+        id: String,
+    },
+    // paths are the daemon's, relative ones are resolved against its working directory
+    PutFile {
+        path: PathBuf,
+    },
+    GetFile {
+        hash: String,
+        output: Option<PathBuf>,
+    },
+    // shows the log filter, or replaces it with filter, e.g. "info,kademlia::network=debug"
+    LogLevel {
+        filter: Option<String>,
+    },
+    // stops the node, see runtime.rs, with hand_off it hands its records over first
+    Shutdown {
+        #[serde(default)]
+        hand_off: bool,
+    },
+}
+
+// the methods Request deserializes, to tell an unknown method from bad params
+const METHODS: &[&str] = &[
+    "store",
+    "get",
+    "trace_get",
+    "delete",
+    "list",
+    "routing_table",
+    "stats",
+    "ping",
+    "find_node",
+    "put_file",
+    "get_file",
+    "log_level",
+    "shutdown",
+];
+
+fn one() -> usize {
+    1
+}
+
+// what a request returns, as text for people and as JSON for scripts and the control socket
+pub struct Outcome {
+    pub text: String,
+    pub json: Value,
+}
+
+pub fn handle<T: Storage>(
+    node: &Arc<Mutex<Node<T>>>,
+    request: Request,
+    shutdown: &Shutdown,
+) -> Result<Outcome> {
+    let outcome = match request {
+        Request::Store {
+            key,
+            value,
+            min_replicas,
+            coded,
+        } => {
+            let node = node.lock().unwrap();
+            let report = if coded {
+                node.store_erasure_coded(
+                    key,
+                    value,
+                    config::get().records.erasure_data_shards,
+                    config::get().records.erasure_parity_shards,
+                )?
+            } else {
+                node.store(key, value)?
+            };
+            confirmed(report, min_replicas)?
+        }
+        Request::Get { key } => {
+            let value = node.lock().unwrap().get_value(key.clone()).ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("no value found for {}", key))
+            })?;
+            Outcome {
+                json: json!({ "key": key, "value": value }),
+                text: value,
+            }
+        }
+        Request::TraceGet { key } => {
+            let (value, trace) = node.lock().unwrap().get_value_traced(key.clone());
+            let found = match &value {
+                Some(value) => format!("value: {}", value),
+                None => format!("no value found for {}", key),
+            };
+            Outcome {
+                text: format!("{}\n{}", trace, found),
+                json: json!({ "key": key, "value": value, "trace": trace.to_json() }),
+            }
+        }
+        Request::Delete { key } => confirmed(node.lock().unwrap().delete(key)?, 1)?,
+        Request::List {
+            prefix,
+            start,
+            end,
+            cursor,
+            limit,
+        } => {
+            let range = KeyRange { prefix, start, end };
+            let limit = limit.unwrap_or(LIST_PAGE_SIZE).min(LIST_PAGE_SIZE);
+            let page = node
+                .lock()
+                .unwrap()
+                .storage
+                .list(&range, cursor.as_deref(), limit)?;
+            Outcome {
+                text: page
+                    .entries
+                    .iter()
+                    .map(|(key, value)| format!("{} {}", key, value))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: json!({
+                    "entries": page
+                        .entries
+                        .iter()
+                        .map(|(key, value)| json!({ "key": key, "value": value }))
+                        .collect::<Vec<_>>(),
+                    "next_cursor": page.next_cursor,
+                }),
+            }
+        }
+        Request::RoutingTable {} => {
+            let node = node.lock().unwrap();
+            let contacts: Vec<(usize, &Contact)> = node
+                .routing_table
+                .buckets
+                .iter()
+                .flat_map(|bucket| bucket.nodes.iter().map(move |contact| (bucket.i, contact)))
+                .collect();
+            Outcome {
+                text: contacts
+                    .iter()
+                    .map(|(bucket, contact)| format!("{} {}", bucket, contact_line(contact)))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: Value::Array(
+                    contacts
+                        .iter()
+                        .map(|(bucket, contact)| {
+                            let mut json = contact_json(contact);
+                            json["bucket"] = json!(bucket);
+                            json
+                        })
+                        .collect(),
+                ),
+            }
+        }
+        Request::Stats {} => {
+            let node = node.lock().unwrap();
+            let stats = node.storage.stats()?;
+            let contacts: usize = node
+                .routing_table
+                .buckets
+                .iter()
+                .map(|bucket| bucket.nodes.len())
+                .sum();
+            Outcome {
+                text: format!("{}, {} contacts", stats, contacts),
+                json: json!({
+                    "node": contact_json(&node.contact),
+                    "storage": stats,
+                    "contacts": contacts,
+                }),
+            }
+        }
+        Request::LogLevel { filter } => {
+            if let Some(filter) = filter {
+                let filter = filter
+                    .parse()
+                    .map_err(|e: String| Error::new(ErrorKind::InvalidInput, e))?;
+                logging::set_filter(filter);
+            }
+            let filter = logging::filter().to_string();
+            Outcome {
+                text: format!("log filter: {}", filter),
+                json: json!({ "filter": filter }),
+            }
+        }
+        Request::Ping { address } => {
+            let address = socket_address(&address)?;
+            let start = Instant::now();
+            let contact = node
+                .lock()
+                .unwrap()
+                .ping(address, config::response_timeout())?
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::TimedOut, format!("no answer from {}", address))
+                })?;
+            let rtt_ms = start.elapsed().as_millis();
+            Outcome {
+                text: format!(
+                    "PONG from {} ({}) in {} ms",
+                    address,
+                    contact.node_id.to_hex(),
+                    rtt_ms
+                ),
+                json: json!({
+                    "address": address.to_string(),
+                    "node_id": contact.node_id.to_hex(),
+                    "rtt_ms": rtt_ms,
+                }),
+            }
+        }
+        Request::FindNode { id } => {
+            let id = parse_hash(&id, "id")?;
+            let contacts = node.lock().unwrap().iterative_lookup_nodes(id);
+            Outcome {
+                text: contacts
+                    .iter()
+                    .map(contact_line)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: Value::Array(contacts.iter().map(contact_json).collect()),
+            }
+        }
+        Request::PutFile { path } => {
+            let (hash, report) = chunking::put_file(node, &path)?;
+            Outcome {
+                text: hash.to_hex(),
+                json: json!({
+                    "hash": hash.to_hex(),
+                    "chunks": report.chunks,
+                    "transferred": report.transferred,
+                    "resumed": report.resumed,
+                }),
+            }
+        }
+        Request::GetFile { hash, output } => {
+            let hash = parse_hash(&hash, "hash")?;
+            let (path, report) = chunking::get_file(node, &hash, output.as_deref())?;
+            Outcome {
+                text: path.display().to_string(),
+                json: json!({
+                    "path": path,
+                    "chunks": report.chunks,
+                    "transferred": report.transferred,
+                    "resumed": report.resumed,
+                }),
+            }
+        }
+        Request::Shutdown { hand_off } => {
+            if hand_off {
+                shutdown.request_with_hand_off();
+            } else {
+                shutdown.request();
+            }
+            Outcome {
+                text: "shutting down".to_string(),
+                json: json!({ "shutting_down": true }),
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+fn confirmed(report: StoreReport, min_replicas: usize) -> Result<Outcome> {
+    if !report.meets(min_replicas) {
+        return Err(Error::other(format!(
+            "only {} of the required {} replicas confirmed {}",
+            report.replicas(),
+            min_replicas,
+            report.key
+        )));
+    }
+    Ok(Outcome {
+        text: report.to_string(),
+        json: report_json(&report),
+    })
+}
+
+pub fn report_json(report: &StoreReport) -> Value {
+    json!({
+        "key": report.key,
+        "confirmed": report.confirmed.iter().map(contact_json).collect::<Vec<_>>(),
+        "rejected": report
+            .rejected
+            .iter()
+            .map(|(contact, reason)| json!({ "node": contact_json(contact), "reason": reason }))
+            .collect::<Vec<_>>(),
+        "timed_out": report.timed_out.iter().map(contact_json).collect::<Vec<_>>(),
+    })
+}
+
+pub fn contact_json(contact: &Contact) -> Value {
+    json!({
+        "node_id": contact.node_id.to_hex(),
+        "address": format!("{}:{}", contact.ip_address, contact.port),
+    })
+}
+
+fn contact_line(contact: &Contact) -> String {
+    format!(
+        "{} {}:{}",
+        contact.node_id.to_hex(),
+        contact.ip_address,
+        contact.port
+    )
+}
+
+fn parse_hash(hex: &str, what: &str) -> Result<SHA> {
+    SHA::from_hex(hex).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid {}, expected 40 hex characters", what),
+        )
+    })
+}
+
+pub fn socket_address(address: &str) -> Result<SocketAddr> {
+    address.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid address {}, expected ip:port", address),
+        )
+    })
+}
+
+// answers requests on the socket until the shutdown is requested, then waits for the
+// requests in progress. A socket file left behind by a node that crashed is replaced, the
+// data directory's lock already makes sure no other node is using it
+pub fn serve<T: Storage + Send + 'static>(
+    node: &Arc<Mutex<Node<T>>>,
+    path: &Path,
+    shutdown: &Arc<Shutdown>,
+) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    listener.set_nonblocking(true)?;
+    logInfo!("Listening for control requests on {}", path.display());
+
+    let mut connections: Vec<thread::JoinHandle<()>> = Vec::new();
+    while !shutdown.is_requested() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let node = Arc::clone(node);
+                let shutdown = Arc::clone(shutdown);
+                connections.retain(|connection| !connection.is_finished());
+                connections.push(thread::spawn(move || {
+                    if let Err(e) = serve_connection(&node, stream, &shutdown) {
+                        logError!("Control connection failed: {}", e);
+                    }
+                }));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(config::poll_interval());
+            }
+            Err(e) => logError!("Failed to accept a control connection: {}", e),
+        }
+    }
+    drop(listener);
+    let _ = fs::remove_file(path);
+    for connection in connections {
+        let _ = connection.join();
+    }
+    Ok(())
+}
+
+// only the user running the node gets to control it: the socket is bound in a directory only
+// that user can enter and made private before it is moved to path, so it is never reachable
+// with the umask's permissions
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the socket path has no file name"))?;
+    let staging = path.with_file_name(format!(".{}.bind", name.to_string_lossy()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+// reads with a timeout so an idle client doesn't keep the connection, and the shutdown,
+// waiting. A request that is being answered is finished first
+fn serve_connection<T: Storage>(
+    node: &Arc<Mutex<Node<T>>>,
+    stream: UnixStream,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(config::poll_interval()))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while !shutdown.is_requested() {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) if line.ends_with(b"\n") => {
+                let request = String::from_utf8_lossy(&line).trim().to_string();
+                line.clear();
+                if request.is_empty() {
+                    continue;
+                }
+                writeln!(writer, "{}", respond(node, &request, shutdown))?;
+            }
+            // the client closed the connection in the middle of a line
+            Ok(_) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn respond<T: Storage>(node: &Arc<Mutex<Node<T>>>, line: &str, shutdown: &Shutdown) -> Value {
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => return error_response(Value::Null, PARSE_ERROR, e.to_string()),
+    };
+    let id = message.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = message.get("method").and_then(Value::as_str) else {
+        return error_response(id, INVALID_REQUEST, "missing method".to_string());
+    };
+    if !METHODS.contains(&method) {
+        return error_response(id, METHOD_NOT_FOUND, format!("unknown method {}", method));
+    }
+    let params = message.get("params").cloned().unwrap_or_else(|| json!({}));
+    let request = match serde_json::from_value(json!({ "method": method, "params": params })) {
+        Ok(request) => request,
+        Err(e) => return error_response(id, INVALID_PARAMS, e.to_string()),
+    };
+    match handle(node, request, shutdown) {
+        // text is the result the way the one-shot commands print it
+        Ok(outcome) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": { "data": outcome.json, "text": outcome.text },
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => error_response(id, NOT_FOUND, e.to_string()),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            error_response(id, INVALID_PARAMS, e.to_string())
+        }
+        Err(e) => error_response(id, FAILED, e.to_string()),
+    }
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+// sends one request to a daemon and waits for its result, an error answer comes back as an
+// Error of the matching kind. Not reaching the daemon is ConnectionRefused whatever the cause,
+// a missing socket isn't a missing key
+pub fn call(path: &Path, method: &str, params: Value) -> Result<Value> {
+    Ok(exchange(path, method, params)?["result"]["data"].take())
+}
+
+// the same for a request of our own, with the text the daemon printed it as
+pub fn call_request(path: &Path, request: &Request) -> Result<Outcome> {
+    let request = serde_json::to_value(request)?;
+    let method = request["method"].as_str().unwrap_or_default();
+    let mut response = exchange(path, method, request["params"].clone())?;
+    let json = response["result"]["data"].take();
+    let text = match response["result"]["text"].as_str() {
+        Some(text) => text.to_string(),
+        None => json.to_string(),
+    };
+    Ok(Outcome { text, json })
+}
+
+fn exchange(path: &Path, method: &str, params: Value) -> Result<Value> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("no daemon is listening on {}: {}", path.display(), e),
+        )
+    })?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid answer from the daemon: {}", e),
+        )
+    })?;
+    if let Some(error) = response.get("error") {
+        let kind = match error["code"].as_i64() {
+            Some(NOT_FOUND) => ErrorKind::NotFound,
+            Some(METHOD_NOT_FOUND | INVALID_PARAMS | INVALID_REQUEST) => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        return Err(Error::new(
+            kind,
+            error["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_string(),
+        ));
+    }
+    Ok(response)
+}
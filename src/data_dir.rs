@@ -0,0 +1,151 @@
+use crate::cli::InitArgs;
+use crate::config::{DEFAULT_DATA_ROOT, SOCKET_FILE};
+use crate::sha::SHA;
+use crate::{logError, logInfo};
+use regex::Regex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+// the database every node used to share in the current directory
+const LEGACY_DATABASE: &str = "local_database.sqlite3";
+
+// everything a node keeps on disk lives in its own directory:
+//
+//   <data-dir>/LOCK                 held for as long as the node runs
+//   <data-dir>/metadata.json        node id, port, bootstrap address and signing key
+//   <data-dir>/storage.sqlite3      (or storage.sled) the stored records
+//   <data-dir>/routing_table.json   the contacts we knew about when we last saved
+//   <data-dir>/uploads/<hash>       the chunks of an unfinished put_file the network confirmed
+//   <data-dir>/kade.sock            a daemon's control socket, while it runs
+#[derive(Debug)]
+pub struct DataDir {
+    pub root: PathBuf,
+    // the OS drops the lock when the file is closed, so a crashed node never leaves a stale one
+    _lock: File,
+}
+
+impl DataDir {
+    // --data-dir if given, otherwise kade_data/<name> in the current directory
+    pub fn from_args(args: &InitArgs) -> Result<Self> {
+        let dir = Self::open(Self::root_for(&args.name, args.data_dir.as_deref()))?;
+        dir.adopt_legacy_files(&args.name);
+        Ok(dir)
+    }
+
+    pub fn root_for(name: &str, data_dir: Option<&Path>) -> PathBuf {
+        match data_dir {
+            Some(data_dir) => data_dir.to_path_buf(),
+            None => Path::new(DEFAULT_DATA_ROOT).join(Self::file_name(name)),
+        }
+    }
+
+    // a fresh directory for a node that only lives for one command, whoever runs the command
+    // removes it afterwards
+    pub fn temporary() -> Result<Self> {
+        Self::open(std::env::temp_dir().join(format!(
+            "kade-{}-{}",
+            std::process::id(),
+            SHA::generate().to_hex()
+        )))
+    }
+
+    pub fn open(root: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&root)?;
+        let mut lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(root.join("LOCK"))?;
+        if lock.try_lock().is_err() {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                format!(
+                    "{} is already in use by another node process",
+                    root.display()
+                ),
+            ));
+        }
+        // the pid is only there to help whoever finds the directory locked
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+        Ok(Self { root, _lock: lock })
+    }
+
+    pub fn metadata_path(&self) -> PathBuf {
+        self.root.join("metadata.json")
+    }
+
+    pub fn sqlite_path(&self) -> PathBuf {
+        self.root.join("storage.sqlite3")
+    }
+
+    pub fn sled_path(&self) -> PathBuf {
+        self.root.join("storage.sled")
+    }
+
+    pub fn routing_table_path(&self) -> PathBuf {
+        self.root.join("routing_table.json")
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.root.join(SOCKET_FILE)
+    }
+
+    pub fn upload_path(&self, manifest_hash: &SHA) -> PathBuf {
+        self.root.join("uploads").join(manifest_hash.to_hex())
+    }
+
+    // node names may contain whitespace, which we don't want in paths
+    fn file_name(name: &str) -> String {
+        let regex = Regex::new(r"\s+").unwrap();
+        regex.replace_all(name, "_").into_owned()
+    }
+
+    // nodes used to write <name>_metadata into the current directory, move it in so the node
+    // keeps its id and key, along with the records of the database they shared
+    fn adopt_legacy_files(&self, name: &str) {
+        let legacy = PathBuf::from(format!("{}_metadata", Self::file_name(name)));
+        if !legacy.exists() || self.metadata_path().exists() {
+            return;
+        }
+        match fs::rename(&legacy, self.metadata_path()) {
+            Ok(()) => logInfo!("Moved {} into {}", legacy.display(), self.root.display()),
+            Err(_) => {
+                // rename fails across filesystems, fall back to a copy
+                let _ = fs::copy(&legacy, self.metadata_path());
+            }
+        }
+        self.adopt_legacy_database();
+    }
+
+    // copied rather than moved, the other nodes that shared it adopt it too. The write-ahead
+    // log may hold records that aren't in the database file yet
+    fn adopt_legacy_database(&self) {
+        if !Path::new(LEGACY_DATABASE).exists() || self.sqlite_path().exists() {
+            return;
+        }
+        let mut copied = Vec::new();
+        for suffix in ["", "-wal", "-shm"] {
+            let from = PathBuf::from(format!("{}{}", LEGACY_DATABASE, suffix));
+            let to = PathBuf::from(format!("{}{}", self.sqlite_path().display(), suffix));
+            if !from.exists() {
+                continue;
+            }
+            if let Err(e) = fs::copy(&from, &to) {
+                logError!("Failed to copy {}: {}", from.display(), e);
+                // half a database is worse than none
+                for path in copied {
+                    let _ = fs::remove_file(path);
+                }
+                return;
+            }
+            copied.push(to);
+        }
+        logInfo!(
+            "Copied {} into {}",
+            LEGACY_DATABASE,
+            self.sqlite_path().display()
+        );
+    }
+}
@@ -10,8 +10,8 @@ pub struct Distance(pub [u8; 20]);
 impl Distance {
     pub fn new(a: &SHA, b: &SHA) -> Self {
         let mut dis = [0u8; 20];
-        for i in 0..20 {
-            dis[i] = a.0[i] ^ b.0[i];
+        for (i, byte) in dis.iter_mut().enumerate() {
+            *byte = a.0[i] ^ b.0[i];
         }
 
         Self(dis)
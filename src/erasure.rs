@@ -0,0 +1,116 @@
+// Reed–Solomon coding of record values: a value is split into data_shards pieces and
+// parity_shards more are computed from them, any data_shards of the lot rebuild the value.
+// The shards are stored as their own records (RecordKind::Shard) under keys derived from the
+// value's key, which land them on different nodes, and the record under the key itself
+// (RecordKind::ErasureCoded) lists their hashes so a reader can tell a good shard from a stale
+// or damaged one.
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+// every shard of a value has the same length, the last data shard is zero padded
+pub fn encode(value: &[u8], data_shards: usize, parity_shards: usize) -> Option<Vec<Vec<u8>>> {
+    let coder = ReedSolomon::new(data_shards, parity_shards).ok()?;
+    let shard_len = value.len().div_ceil(data_shards).max(1);
+    let mut shards: Vec<Vec<u8>> = (0..data_shards + parity_shards)
+        .map(|index| {
+            let start = (index * shard_len).min(value.len());
+            let end = ((index + 1) * shard_len).min(value.len());
+            let mut shard = if index < data_shards {
+                value[start..end].to_vec()
+            } else {
+                Vec::new()
+            };
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect();
+    coder.encode(&mut shards).ok()?;
+    Some(shards)
+}
+
+// rebuilds a value of `size` bytes from the shards we got, None where one is missing.
+// Fails if fewer than data_shards of them are there
+pub fn decode(
+    mut shards: Vec<Option<Vec<u8>>>,
+    data_shards: usize,
+    parity_shards: usize,
+    size: usize,
+) -> Option<Vec<u8>> {
+    let coder = ReedSolomon::new(data_shards, parity_shards).ok()?;
+    coder.reconstruct_data(&mut shards).ok()?;
+    let mut value: Vec<u8> = shards
+        .into_iter()
+        .take(data_shards)
+        .flat_map(|shard| shard.unwrap_or_default())
+        .collect();
+    if value.len() < size {
+        return None;
+    }
+    value.truncate(size);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_with_every_shard() {
+        for size in [0, 1, 5, 1000] {
+            let value = value(size);
+            let shards = encode(&value, 4, 2).unwrap();
+            assert_eq!(shards.len(), 6);
+            assert!(shards.iter().all(|shard| shard.len() == shards[0].len()));
+            let shards = shards.into_iter().map(Some).collect();
+            assert_eq!(decode(shards, 4, 2, size), Some(value));
+        }
+    }
+
+    #[test]
+    fn rebuilds_without_as_many_shards_as_there_is_parity() {
+        let value = value(1001);
+        let shards = encode(&value, 4, 3).unwrap();
+        // the data shards are the ones to miss, so all of them have to be rebuilt from parity
+        for missing in [[0, 1, 2], [1, 3, 5], [4, 5, 6]] {
+            let mut shards: Vec<_> = shards.iter().cloned().map(Some).collect();
+            for index in missing {
+                shards[index] = None;
+            }
+            assert_eq!(decode(shards, 4, 3, value.len()), Some(value.clone()));
+        }
+    }
+
+    #[test]
+    fn fails_with_more_shards_missing() {
+        let value = value(100);
+        let mut shards: Vec<_> = encode(&value, 4, 2)
+            .unwrap()
+            .into_iter()
+            .map(Some)
+            .collect();
+        shards[0] = None;
+        shards[2] = None;
+        shards[5] = None;
+        assert_eq!(decode(shards, 4, 2, value.len()), None);
+    }
+
+    #[test]
+    fn fails_when_the_size_is_too_big() {
+        let value = value(10);
+        let shards = encode(&value, 2, 1)
+            .unwrap()
+            .into_iter()
+            .map(Some)
+            .collect();
+        assert_eq!(decode(shards, 2, 1, 100), None);
+    }
+
+    #[test]
+    fn rejects_impossible_shard_counts() {
+        assert_eq!(encode(b"value", 0, 2), None);
+        assert_eq!(encode(b"value", 200, 100), None);
+    }
+}
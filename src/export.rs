@@ -0,0 +1,260 @@
+use crate::{
+    config::RECEIVE_BUFFER_BYTES,
+    logWarn,
+    message_handler::{check_delete, check_store},
+    record::{Record, Tombstone},
+    storage::{Storage, StorageError, StorageResult},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+use std::str::FromStr;
+
+// a binary export starts with these bytes and a format version, then holds one
+// length-prefixed (u32, little endian) bincode entry after another
+const BINARY_MAGIC: &[u8; 4] = b"KADE";
+const BINARY_VERSION: u8 = 1;
+// imported records are written this many at a time
+const IMPORT_BATCH_SIZE: usize = 500;
+// an entry holds one record, which had to fit in a datagram
+const MAX_ENTRY_BYTES: usize = RECEIVE_BUFFER_BYTES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    // one JSON entry per line, easy to inspect and to generate from other tools
+    JsonLines,
+    Binary,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<Self, Self::Err> {
+        match format {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "binary" | "bin" => Ok(Self::Binary),
+            _ => Err(format!(
+                "unknown export format {}, expected jsonl or binary",
+                format
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportEntry {
+    // a pair stored without a publisher
+    Value { key: String, value: String },
+    Record(Record),
+    Tombstone(Tombstone),
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    // entries a STORE or DELETE would have rejected, or that are already stored
+    pub skipped: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "imported {} entries ({} skipped)",
+            self.imported, self.skipped
+        )
+    }
+}
+
+// writes every pair, record and tombstone in the storage, returns how many were written
+pub fn export<T: Storage>(
+    storage: &T,
+    mut writer: impl Write,
+    format: ExportFormat,
+) -> Result<usize> {
+    if format == ExportFormat::Binary {
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&[BINARY_VERSION])?;
+    }
+    let mut count = 0;
+    storage.for_each_entry(&mut |key, value, record| {
+        let entry = match record {
+            Some(record) => ExportEntry::Record(record),
+            None => ExportEntry::Value { key, value },
+        };
+        count += 1;
+        write_entry(&mut writer, format, &entry).map_err(|e| StorageError {
+            message: e.to_string(),
+        })
+    })?;
+    for tombstone in storage.list_tombstones()? {
+        write_entry(&mut writer, format, &ExportEntry::Tombstone(tombstone))?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// reads an export in either format, telling them apart by the binary header
+pub fn import<T: Storage>(storage: &T, mut reader: impl BufRead) -> Result<ImportReport> {
+    let mut importer = Importer {
+        storage,
+        batch: Vec::new(),
+        batched: HashSet::new(),
+        report: ImportReport::default(),
+    };
+    if reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if header[4] != BINARY_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported export version {}", header[4]),
+            ));
+        }
+        while let Some(entry) = read_binary_entry(&mut reader)? {
+            importer.add(entry)?;
+        }
+    } else {
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, e),
+                )
+            })?;
+            importer.add(entry)?;
+        }
+    }
+    importer.flush()?;
+    Ok(importer.report)
+}
+
+fn write_entry(writer: &mut impl Write, format: ExportFormat, entry: &ExportEntry) -> Result<()> {
+    match format {
+        ExportFormat::JsonLines => {
+            serde_json::to_writer(&mut *writer, entry)?;
+            writer.write_all(b"\n")
+        }
+        ExportFormat::Binary => {
+            let bytes = bincode::serde::encode_to_vec(entry, bincode::config::standard())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            writer.write_all(&bytes)
+        }
+    }
+}
+
+fn read_binary_entry(reader: &mut impl Read) -> Result<Option<ExportEntry>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        // the file may only end between entries
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_ENTRY_BYTES {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "entry of {} bytes, at most {} fit in a record",
+                length, MAX_ENTRY_BYTES
+            ),
+        ));
+    }
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    bincode::serde::decode_from_slice(&bytes, bincode::config::standard())
+        .map(|(entry, _)| Some(entry))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+struct Importer<'a, T: Storage> {
+    storage: &'a T,
+    batch: Vec<Record>,
+    // the keys in the batch, a record is checked against an earlier one for its key
+    batched: HashSet<String>,
+    report: ImportReport,
+}
+
+impl<T: Storage> Importer<'_, T> {
+    // records and tombstones are checked like a STORE or DELETE would check them, an export
+    // may come from anywhere
+    fn add(&mut self, entry: ExportEntry) -> StorageResult<()> {
+        match entry {
+            ExportEntry::Value { key, value } => {
+                // a pair without a publisher doesn't replace anyone's record
+                if self.storage.get_record(&key)?.is_some() {
+                    logWarn!("Skipping the value for key {}, it holds a record", key);
+                    self.report.skipped += 1;
+                    return Ok(());
+                }
+                self.storage.store(&key, &value)?;
+                self.report.imported += 1;
+            }
+            ExportEntry::Record(record) => {
+                if self.batched.contains(&record.key) {
+                    self.flush()?;
+                }
+                match check_store(self.storage, &record, None) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.report.skipped += 1;
+                        return Ok(());
+                    }
+                    Err(reason) => {
+                        logWarn!("Skipping the record for key {}: {}", record.key, reason);
+                        self.report.skipped += 1;
+                        return Ok(());
+                    }
+                }
+                self.batched.insert(record.key.clone());
+                self.batch.push(record);
+                if self.batch.len() >= IMPORT_BATCH_SIZE {
+                    self.flush()?;
+                }
+            }
+            ExportEntry::Tombstone(tombstone) => {
+                // a tombstone drops the record it deletes, which may still be in the batch
+                self.flush()?;
+                match check_delete(self.storage, &tombstone) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.report.skipped += 1;
+                        return Ok(());
+                    }
+                    Err(reason) => {
+                        logWarn!(
+                            "Skipping the tombstone for key {}: {}",
+                            tombstone.key,
+                            reason
+                        );
+                        self.report.skipped += 1;
+                        return Ok(());
+                    }
+                }
+                self.storage.store_tombstone(&tombstone)?;
+                self.report.imported += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.storage.store_records(&self.batch)?;
+        self.report.imported += self.batch.len();
+        self.batch.clear();
+        self.batched.clear();
+        Ok(())
+    }
+}
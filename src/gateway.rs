@@ -0,0 +1,300 @@
+// An HTTP/JSON gateway (`http` feature) for services that can't speak the UDP protocol:
+//
+//   PUT    /v1/keys/{key}            stores the request body under the key
+//   GET    /v1/keys/{key}            looks the value up
+//   DELETE /v1/keys/{key}            publishes a tombstone for the key
+//   GET    /v1/peers                 the routing table
+//   GET    /v1/nodes/{id}/closest    the k closest nodes to a 40 hex character id
+//   GET    /v1/health                200 while the node knows at least one peer, 503 otherwise
+//   GET    /metrics                  the metrics in the Prometheus text format, see metrics.rs
+//
+// serve_metrics answers /metrics alone, for nodes that expose their metrics but not their data.
+// Values travel as the raw body by default. With Content-Type: application/json a PUT body is
+// {"value": "<text>"}, and with Accept: application/json a GET answers {"key": ..., "value": ...}.
+// Storage holds values as text, so a raw body that isn't UTF-8 is refused with 415 rather than
+// altered, and there is no base64 mode for binary values until storage can keep bytes. Errors
+// are JSON, {"error": ...}, with the status code that fits
+use crate::{
+    config::{self, HTTP_MAX_BODY_BYTES},
+    control::{self, Request as ControlRequest},
+    logError, logInfo, metrics,
+    node::Node,
+    runtime::Shutdown,
+    storage::Storage,
+    store_report::StoreReport,
+};
+use serde_json::{Value, json};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+type HttpResponse = Response<std::io::Cursor<Vec<u8>>>;
+type Route<T> = fn(&Arc<Mutex<Node<T>>>, Request) -> Result<()>;
+
+pub fn serve<T: Storage + Send + 'static>(
+    node: &Arc<Mutex<Node<T>>>,
+    address: &str,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    logInfo!("HTTP gateway listening on {}", address);
+    listen(node, address, shutdown, route)
+}
+
+pub fn serve_metrics<T: Storage + Send + 'static>(
+    node: &Arc<Mutex<Node<T>>>,
+    address: &str,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    logInfo!("Serving metrics on {}", address);
+    listen(node, address, shutdown, route_metrics)
+}
+
+// answers requests on address, each in its own thread since a lookup can take seconds, until
+// the shutdown is requested, then waits for the requests in progress
+fn listen<T: Storage + Send + 'static>(
+    node: &Arc<Mutex<Node<T>>>,
+    address: &str,
+    shutdown: &Shutdown,
+    route: Route<T>,
+) -> Result<()> {
+    let server = Server::http(address).map_err(|e| Error::other(e.to_string()))?;
+    let mut requests: Vec<thread::JoinHandle<()>> = Vec::new();
+    while !shutdown.is_requested() {
+        let Some(request) = server.recv_timeout(config::poll_interval())? else {
+            continue;
+        };
+        let node = Arc::clone(node);
+        requests.retain(|request| !request.is_finished());
+        requests.push(thread::spawn(move || {
+            if let Err(e) = route(&node, request) {
+                logError!("Failed to answer an HTTP request: {}", e);
+            }
+        }));
+    }
+    for request in requests {
+        let _ = request.join();
+    }
+    Ok(())
+}
+
+fn route<T: Storage>(node: &Arc<Mutex<Node<T>>>, mut request: Request) -> Result<()> {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method = request.method().clone();
+
+    let response = match (segments.as_slice(), &method) {
+        (["v1", "keys", key], _) if !key.is_empty() => match percent_decode(key) {
+            None => error(400, "the key isn't valid percent-encoded UTF-8"),
+            Some(key) => match method {
+                Method::Get => get_key(node, key, accepts_json(&request)),
+                Method::Put => {
+                    let as_json = sends_json(&request);
+                    match read_body(&mut request) {
+                        Err(e) => error(400, &format!("failed to read the body: {}", e)),
+                        Ok(body) => put_key(node, key, &body, as_json),
+                    }
+                }
+                Method::Delete => delete_key(node, key),
+                _ => not_allowed("GET, PUT, DELETE"),
+            },
+        },
+        (["v1", "peers"], Method::Get) => control_response(node, ControlRequest::RoutingTable {}),
+        (["v1", "nodes", id, "closest"], Method::Get) => {
+            control_response(node, ControlRequest::FindNode { id: id.to_string() })
+        }
+        (["v1", "health"], Method::Get) => health(node),
+        (["metrics"], Method::Get) => metrics_response(node),
+        (["v1", "peers"] | ["v1", "nodes", _, "closest"] | ["v1", "health"] | ["metrics"], _) => {
+            not_allowed("GET")
+        }
+        _ => error(404, &format!("no such endpoint {}", path)),
+    };
+    request.respond(response)
+}
+
+fn route_metrics<T: Storage>(node: &Arc<Mutex<Node<T>>>, request: Request) -> Result<()> {
+    let response = match (request.url().split('?').next(), request.method()) {
+        (Some("/metrics"), Method::Get) => metrics_response(node),
+        (Some("/metrics"), _) => not_allowed("GET"),
+        (path, _) => error(
+            404,
+            &format!("no such endpoint {}", path.unwrap_or_default()),
+        ),
+    };
+    request.respond(response)
+}
+
+// reads at most one byte more than a value may have, so put_key can tell it's too big
+fn read_body(request: &mut Request) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(HTTP_MAX_BODY_BYTES as u64 + 1)
+        .read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn get_key<T: Storage>(node: &Arc<Mutex<Node<T>>>, key: String, as_json: bool) -> HttpResponse {
+    let Some(value) = node.lock().unwrap().get_value(key.clone()) else {
+        return error(404, &format!("no value found for {}", key));
+    };
+    if as_json {
+        return json_response(200, &json!({ "key": key, "value": value }));
+    }
+    Response::from_data(value.into_bytes()).with_header(content_type("application/octet-stream"))
+}
+
+fn put_key<T: Storage>(
+    node: &Arc<Mutex<Node<T>>>,
+    key: String,
+    body: &[u8],
+    as_json: bool,
+) -> HttpResponse {
+    if body.len() > HTTP_MAX_BODY_BYTES {
+        return error(
+            413,
+            &format!("values are limited to {} bytes", HTTP_MAX_BODY_BYTES),
+        );
+    }
+    let value = if as_json {
+        let value = serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|body| body.get("value")?.as_str().map(str::to_string));
+        let Some(value) = value else {
+            return error(400, "expected {\"value\": \"<text>\"}");
+        };
+        value
+    } else {
+        match String::from_utf8(body.to_vec()) {
+            Ok(value) => value,
+            Err(_) => {
+                return error(
+                    415,
+                    "values are stored as text, binary values aren't supported: send UTF-8",
+                );
+            }
+        }
+    };
+    let report = node.lock().unwrap().store(key, value);
+    report_response(report)
+}
+
+fn delete_key<T: Storage>(node: &Arc<Mutex<Node<T>>>, key: String) -> HttpResponse {
+    let report = node.lock().unwrap().delete(key);
+    report_response(report)
+}
+
+// 200 when at least one node confirmed the write, 503 when none did
+fn report_response(report: Result<StoreReport>) -> HttpResponse {
+    match report {
+        Ok(report) if report.meets(1) => json_response(200, &control::report_json(&report)),
+        Ok(report) => json_response(503, &control::report_json(&report)),
+        Err(e) => io_error(&e),
+    }
+}
+
+fn health<T: Storage>(node: &Arc<Mutex<Node<T>>>) -> HttpResponse {
+    let node = node.lock().unwrap();
+    let contacts: usize = node
+        .routing_table
+        .buckets
+        .iter()
+        .map(|bucket| bucket.nodes.len())
+        .sum();
+    let status = if contacts > 0 { 200 } else { 503 };
+    json_response(
+        status,
+        &json!({
+            "status": if contacts > 0 { "ok" } else { "isolated" },
+            "node": control::contact_json(&node.contact),
+            "contacts": contacts,
+        }),
+    )
+}
+
+fn metrics_response<T: Storage>(node: &Arc<Mutex<Node<T>>>) -> HttpResponse {
+    let body = metrics::render(&node.lock().unwrap());
+    Response::from_data(body.into_bytes())
+        .with_header(content_type("text/plain; version=0.0.4; charset=utf-8"))
+}
+
+fn control_response<T: Storage>(
+    node: &Arc<Mutex<Node<T>>>,
+    request: ControlRequest,
+) -> HttpResponse {
+    match control::handle(node, request, &Shutdown::default()) {
+        Ok(outcome) => json_response(200, &outcome.json),
+        Err(e) => io_error(&e),
+    }
+}
+
+fn io_error(e: &Error) -> HttpResponse {
+    let status = match e.kind() {
+        ErrorKind::NotFound => 404,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => 400,
+        ErrorKind::TimedOut => 504,
+        _ => 500,
+    };
+    error(status, &e.to_string())
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn not_allowed(allow: &str) -> HttpResponse {
+    error(405, "method not allowed").with_header(header("Allow", allow))
+}
+
+fn json_response(status: u16, body: &Value) -> HttpResponse {
+    Response::from_data(body.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn content_type(value: &str) -> Header {
+    header("Content-Type", value)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn header_contains(request: &Request, field: &'static str, needle: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .any(|header| header.field.equiv(field) && header.value.as_str().contains(needle))
+}
+
+fn accepts_json(request: &Request) -> bool {
+    header_contains(request, "Accept", "application/json")
+}
+
+fn sends_json(request: &Request) -> bool {
+    header_contains(request, "Content-Type", "application/json")
+}
+
+// keys may contain anything, so clients percent-encode them in the path
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
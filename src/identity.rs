@@ -0,0 +1,84 @@
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Copy, PartialEq, Eq, Hash, Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; 32]);
+
+// kept as a Vec because serde only derives arrays up to 32 bytes
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Signature(pub Vec<u8>);
+
+impl PublicKey {
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(PublicKey(key))
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.0) else {
+            return false;
+        };
+        let Ok(signature) = ed25519_dalek::Signature::from_slice(&signature.0) else {
+            return false;
+        };
+        key.verify(message, &signature).is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// the key pair a node signs its records with, the public half identifies it as a publisher
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_secret(&Self::generate_secret())
+    }
+
+    pub fn generate_secret() -> [u8; 32] {
+        let mut secret = [0u8; 32];
+        rand::rng().fill(&mut secret);
+        secret
+    }
+
+    pub fn from_secret(secret: &[u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature(self.signing_key.sign(message).to_bytes().to_vec())
+    }
+}
+
+// never print the secret half
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
@@ -1,13 +1,33 @@
+pub mod anti_entropy;
 pub mod bucket;
+pub mod chunking;
 pub mod cli;
+pub mod client;
 pub mod config;
 pub mod contact;
+pub mod control;
+pub mod data_dir;
 pub mod distance;
+pub mod erasure;
+pub mod export;
+#[cfg(feature = "http")]
+pub mod gateway;
+pub mod identity;
 pub mod logging;
+pub mod lookup_trace;
+pub mod memory_storage;
 pub mod message_handler;
+pub mod metrics;
+pub mod migrations;
 pub mod network;
 pub mod node;
 pub mod node_metadata;
+pub mod record;
 pub mod routing_table;
+pub mod runtime;
 pub mod sha;
+#[cfg(feature = "sled")]
+pub mod sled_storage;
 pub mod storage;
+pub mod store_report;
+pub mod token;
@@ -1,78 +1,539 @@
-use chrono::Utc;
-use std::{fmt, panic::Location, sync::OnceLock};
+// Leveled logging for the whole crate. Messages go through a filter that can be changed while
+// the node runs, then to one sink: the console (the default), a rotating file, or, for
+// applications that embed the node and have their own subscriber, the `log` or `tracing`
+// ecosystem (features of the same names).
+//
+// The filter is a comma separated list of directives, like RUST_LOG: a level on its own sets
+// the default, module=level sets it for a module and everything in it, and the longest
+// matching module wins, e.g. "info,kademlia::network=trace,kademlia::storage=off"
+use chrono::{SecondsFormat, Utc};
+use serde_json::json;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    panic::Location,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Mutex, RwLock,
+        atomic::{AtomicU8, Ordering},
+    },
+};
 
-pub trait Logging {
-    #[track_caller]
-    fn log(&self, args: fmt::Arguments);
-    #[track_caller]
-    fn warn(&self, args: fmt::Arguments);
-    #[track_caller]
-    fn error(&self, args: fmt::Arguments);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    // None is off
+    fn parse(level: &str) -> Result<Option<Self>, String> {
+        match level.to_ascii_lowercase().as_str() {
+            "off" => Ok(None),
+            "error" => Ok(Some(Level::Error)),
+            "warn" => Ok(Some(Level::Warn)),
+            "info" => Ok(Some(Level::Info)),
+            "debug" => Ok(Some(Level::Debug)),
+            "trace" => Ok(Some(Level::Trace)),
+            _ => Err(format!(
+                "unknown log level {}, expected off, error, warn, info, debug or trace",
+                level
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    default: Option<Level>,
+    modules: Vec<(String, Option<Level>)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Filter {
+    const DEFAULT: Self = Self {
+        default: Some(Level::Info),
+        modules: Vec::new(),
+    };
 
-    #[track_caller]
-    fn format_message(level: &str, args: fmt::Arguments) -> String {
-        let now = Utc::now();
-        let timestamp = now.format("%Y-%m-%dT%H:%M:%S").to_string();
-        let caller_location = Location::caller();
-        let file = caller_location.file();
-        let line = caller_location.line();
-        format!("[{}][{}][{}:{}] {}", timestamp, level, file, line, args)
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let max = self
+            .modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, max)| *max);
+        max.is_some_and(|max| level <= max)
+    }
+
+    // the most verbose level any module gets, to skip the filter for everything above it
+    fn max_level(&self) -> u8 {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .chain([self.default])
+            .map(|level| level.map_or(0, |level| level as u8))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut filter = Self::DEFAULT;
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                None => filter.default = Level::parse(directive)?,
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(format!("missing the module in {}", directive));
+                    }
+                    filter.modules.retain(|(prefix, _)| prefix != module);
+                    filter
+                        .modules
+                        .push((module.to_string(), Level::parse(level.trim())?));
+                }
+            }
+        }
+        Ok(filter)
     }
 }
 
-pub struct ConsoleLogging;
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |level: Option<Level>| level.map_or("off", |level| level.as_str());
+        write!(f, "{}", name(self.default).to_ascii_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, name(*level).to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // [timestamp][LEVEL][file:line] message
+    Text,
+    // one JSON object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown log format {}, expected text or json",
+                format
+            )),
+        }
+    }
+}
+
+// one message that made it through the filter
+pub struct Entry<'a> {
+    pub level: Level,
+    pub module: &'a str,
+    pub file: &'a str,
+    pub line: u32,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl Entry<'_> {
+    pub fn format(&self, format: Format) -> String {
+        match format {
+            Format::Text => format!(
+                "[{}][{}][{}:{}] {}",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S"),
+                self.level.as_str(),
+                self.file,
+                self.line,
+                self.args
+            ),
+            Format::Json => json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": self.level.as_str(),
+                "module": self.module,
+                "file": self.file,
+                "line": self.line,
+                "message": self.args.to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+// where the messages that pass the filter end up
+pub trait Logging: Send + Sync {
+    fn log(&self, entry: &Entry);
+}
+
+// where the console sink writes info and lower, errors always go to stderr. One-shot commands
+// print their result on stdout, so they move the node's logs off it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout = 0,
+    Stderr = 1,
+    Off = 2,
+}
+
+static OUTPUT: AtomicU8 = AtomicU8::new(LogOutput::Stdout as u8);
+
+pub fn set_output(output: LogOutput) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+pub struct ConsoleLogging {
+    format: Format,
+}
+
+impl Default for ConsoleLogging {
+    fn default() -> Self {
+        Self::new(Format::Text)
+    }
+}
 
 impl ConsoleLogging {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(format: Format) -> Self {
+        Self { format }
     }
 }
 
 impl Logging for ConsoleLogging {
-    fn log(&self, args: fmt::Arguments) {
-        println!("{}", Self::format_message("INFO", args));
+    fn log(&self, entry: &Entry) {
+        if entry.level == Level::Error {
+            eprintln!("{}", entry.format(self.format));
+            return;
+        }
+        match OUTPUT.load(Ordering::Relaxed) {
+            0 => println!("{}", entry.format(self.format)),
+            1 => eprintln!("{}", entry.format(self.format)),
+            _ => {}
+        }
     }
+}
 
-    fn warn(&self, args: fmt::Arguments) {
-        println!("{}", Self::format_message("WARN", args));
+// appends to a file, and once it would grow past max_bytes renames it to <file>.1, <file>.1
+// to <file>.2 and so on, keeping that many old files. Errors are printed on stderr as well, so
+// a node that fails to start still says why
+pub struct FileLogging {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    format: Format,
+    // the open file and how big it is
+    file: Mutex<(File, u64)>,
+}
+
+impl FileLogging {
+    pub fn open(path: &Path, max_bytes: u64, keep: usize, format: Format) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            format,
+            file: Mutex::new((file, size)),
+        })
     }
 
-    fn error(&self, args: fmt::Arguments) {
-        eprintln!("{}", Self::format_message("ERROR", args));
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        File::create(&self.path)
+    }
+}
+
+impl Logging for FileLogging {
+    fn log(&self, entry: &Entry) {
+        if entry.level == Level::Error {
+            eprintln!("{}", entry.format(Format::Text));
+        }
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut guard = self.file.lock().unwrap();
+        let (file, size) = &mut *guard;
+        if *size > 0 && *size + line.len() as u64 > self.max_bytes {
+            match self.rotate() {
+                Ok(rotated) => {
+                    *file = rotated;
+                    *size = 0;
+                }
+                Err(e) => eprintln!("Failed to rotate {}: {}", self.path.display(), e),
+            }
+        }
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => *size += line.len() as u64,
+            Err(e) => eprintln!("Failed to write to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// hands the messages to whatever logger the application installed for the `log` crate, with
+// the module as the target
+#[cfg(feature = "log")]
+pub struct LogAdapter;
+
+#[cfg(feature = "log")]
+impl Logging for LogAdapter {
+    fn log(&self, entry: &Entry) {
+        let level = match entry.level {
+            Level::Error => log::Level::Error,
+            Level::Warn => log::Level::Warn,
+            Level::Info => log::Level::Info,
+            Level::Debug => log::Level::Debug,
+            Level::Trace => log::Level::Trace,
+        };
+        log::logger().log(
+            &log::Record::builder()
+                .level(level)
+                .target(entry.module)
+                .module_path(Some(entry.module))
+                .file(Some(entry.file))
+                .line(Some(entry.line))
+                .args(entry.args)
+                .build(),
+        );
     }
 }
 
-pub struct LoggingFactory;
+// emits the messages as `tracing` events. Their target has to be known at compile time, so it
+// is "kademlia" and the module is a field
+#[cfg(feature = "tracing")]
+pub struct TracingAdapter;
 
-impl LoggingFactory {
-    pub fn logger() -> &'static impl Logging {
-        static LOGGER: OnceLock<ConsoleLogging> = OnceLock::new();
-        LOGGER.get_or_init(|| ConsoleLogging::new())
+#[cfg(feature = "tracing")]
+impl Logging for TracingAdapter {
+    fn log(&self, entry: &Entry) {
+        macro_rules! event {
+            ($level:expr) => {
+                tracing::event!(
+                    target: "kademlia",
+                    $level,
+                    module = entry.module,
+                    file = entry.file,
+                    line = entry.line,
+                    "{}",
+                    entry.args
+                )
+            };
+        }
+        match entry.level {
+            Level::Error => event!(tracing::Level::ERROR),
+            Level::Warn => event!(tracing::Level::WARN),
+            Level::Info => event!(tracing::Level::INFO),
+            Level::Debug => event!(tracing::Level::DEBUG),
+            Level::Trace => event!(tracing::Level::TRACE),
+        }
     }
 }
 
+static FILTER: RwLock<Filter> = RwLock::new(Filter::DEFAULT);
+// the most verbose level the filter lets through for any module, checked before taking the lock
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+// None is the console with text lines
+static SINK: RwLock<Option<Box<dyn Logging>>> = RwLock::new(None);
+
+pub fn set_filter(filter: Filter) {
+    let mut current = FILTER.write().unwrap();
+    MAX_LEVEL.store(filter.max_level(), Ordering::Relaxed);
+    *current = filter;
+}
+
+pub fn filter() -> Filter {
+    FILTER.read().unwrap().clone()
+}
+
+pub fn set_sink(sink: Box<dyn Logging>) {
+    *SINK.write().unwrap() = Some(sink);
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && FILTER.read().unwrap().enabled(level, module)
+}
+
+#[track_caller]
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let location = Location::caller();
+    let entry = Entry {
+        level,
+        module,
+        file: location.file(),
+        line: location.line(),
+        args,
+    };
+    match &*SINK.read().unwrap() {
+        Some(sink) => sink.log(&entry),
+        None => ConsoleLogging::default().log(&entry),
+    }
+}
 
 #[macro_export]
-macro_rules! logInfo {
-    ($($arg:tt)*) => {{
-        use $crate::logging::Logging; 
-        $crate::logging::LoggingFactory::logger().log(format_args!($($arg)*));
-    }};
+macro_rules! logTrace {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Trace, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! logDebug {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
 }
 
+#[macro_export]
+macro_rules! logInfo {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
 
 #[macro_export]
 macro_rules! logWarn {
-    ($($arg:tt)*) => {{
-        use $crate::logging::Logging; 
-        $crate::logging::LoggingFactory::logger().warn(format_args!($($arg)*))
-    }};
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Warn, module_path!(), format_args!($($arg)*))
+    };
 }
 
 #[macro_export]
 macro_rules! logError {
-    ($($arg:tt)*) => {{
-        use $crate::logging::Logging; 
-        $crate::logging::LoggingFactory::logger().error(format_args!($($arg)*))
-    }};
-}
\ No newline at end of file
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(spec: &str) -> Filter {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn default_level_is_info() {
+        let filter = filter("");
+        assert_eq!(filter, Filter::default());
+        assert!(filter.enabled(Level::Info, "kademlia::node"));
+        assert!(!filter.enabled(Level::Debug, "kademlia::node"));
+    }
+
+    #[test]
+    fn longest_matching_module_wins() {
+        let filter =
+            filter("warn,kademlia=debug,kademlia::network=off,kademlia::network::udp=trace");
+        assert!(!filter.enabled(Level::Info, "other"));
+        assert!(filter.enabled(Level::Debug, "kademlia"));
+        assert!(filter.enabled(Level::Debug, "kademlia::node"));
+        assert!(!filter.enabled(Level::Error, "kademlia::network"));
+        assert!(filter.enabled(Level::Trace, "kademlia::network::udp"));
+        // a module is matched by whole path segments only
+        assert!(filter.enabled(Level::Debug, "kademlia::networking"));
+        assert!(!filter.enabled(Level::Info, "kademliax"));
+    }
+
+    #[test]
+    fn later_directives_override_earlier_ones() {
+        let filter = filter("debug, kademlia=trace, error, kademlia=warn");
+        assert!(!filter.enabled(Level::Warn, "other"));
+        assert!(filter.enabled(Level::Warn, "kademlia"));
+        assert!(!filter.enabled(Level::Info, "kademlia"));
+        assert_eq!(filter.to_string(), "error,kademlia=warn");
+    }
+
+    #[test]
+    fn levels_are_case_insensitive() {
+        assert_eq!(filter("DEBUG,kademlia=Off"), filter("debug,kademlia=off"));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let filter = filter("off,kademlia::storage=trace,kademlia=info");
+        assert_eq!(filter.to_string().parse::<Filter>(), Ok(filter));
+    }
+
+    #[test]
+    fn max_level_is_the_most_verbose() {
+        assert_eq!(filter("off").max_level(), 0);
+        assert_eq!(
+            filter("error,kademlia=debug").max_level(),
+            Level::Debug as u8
+        );
+        assert_eq!(filter("trace,kademlia=off").max_level(), Level::Trace as u8);
+    }
+
+    #[test]
+    fn bad_directives_are_rejected() {
+        assert!(
+            "verbose"
+                .parse::<Filter>()
+                .unwrap_err()
+                .contains("unknown log level verbose")
+        );
+        assert!("kademlia=loud".parse::<Filter>().is_err());
+        assert!(
+            "=debug"
+                .parse::<Filter>()
+                .unwrap_err()
+                .contains("missing the module")
+        );
+        assert!("json".parse::<Format>().is_ok());
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
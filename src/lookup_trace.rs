@@ -0,0 +1,196 @@
+// What an iterative lookup did, to find out why one failed: every node it asked, in order, when
+// and what it answered, the closest nodes it ended up with and why it stopped. See
+// Node::iterative_lookup_nodes_traced and Node::get_value_traced, kade trace-get prints one
+use crate::{contact::Contact, control::contact_json, sha::SHA};
+use serde_json::{Value, json};
+use std::{fmt, time::Duration};
+
+#[derive(Clone, Debug)]
+pub struct LookupTrace {
+    pub target: SHA,
+    // the key of a value lookup, None for a node lookup
+    pub key: Option<String>,
+    pub queries: Vec<Query>,
+    // sorted by distance to the target
+    pub closest: Vec<Contact>,
+    pub stop: StopReason,
+    pub took: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub node: Contact,
+    // the iteration it was sent in, from 1
+    pub round: usize,
+    // since the lookup started
+    pub sent: Duration,
+    // how long the answer took, None if there was none
+    pub answered: Option<Duration>,
+    pub reply: Reply,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    // the closest nodes it knows, and how many of them the lookup hadn't seen yet
+    Nodes { count: usize, new: usize },
+    // seq is set for mutable records
+    Record { seq: Option<u64> },
+    // a record that wasn't for the key or whose signature didn't check out
+    InvalidRecord,
+    Tombstone,
+    Timeout,
+    SendFailed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    FoundLocally,
+    DeletedLocally,
+    Found,
+    Deleted,
+    // fewer than α nodes were left to ask, none of the answers brought closer ones
+    Converged,
+    // every node it knew of was asked
+    Exhausted,
+    IterationLimit,
+    // the routing table was empty
+    NoContacts,
+}
+
+impl StopReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            StopReason::FoundLocally => "found_locally",
+            StopReason::DeletedLocally => "deleted_locally",
+            StopReason::Found => "found",
+            StopReason::Deleted => "deleted",
+            StopReason::Converged => "converged",
+            StopReason::Exhausted => "exhausted",
+            StopReason::IterationLimit => "iteration_limit",
+            StopReason::NoContacts => "no_contacts",
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            StopReason::FoundLocally => "found in local storage",
+            StopReason::DeletedLocally => "deleted, we hold the record and its tombstone",
+            StopReason::Found => "found the value",
+            StopReason::Deleted => "the publisher's tombstone covers the record",
+            StopReason::Converged => "converged, no closer nodes turned up",
+            StopReason::Exhausted => "asked every node it knew of",
+            StopReason::IterationLimit => "reached the iteration limit",
+            StopReason::NoContacts => "the routing table is empty",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Nodes { count, new } => write!(f, "{} nodes, {} new", count, new),
+            Reply::Record { seq: None } => write!(f, "the record"),
+            Reply::Record { seq: Some(seq) } => write!(f, "the record, seq {}", seq),
+            Reply::InvalidRecord => write!(f, "an invalid record"),
+            Reply::Tombstone => write!(f, "the tombstone"),
+            Reply::Timeout => write!(f, "no answer"),
+            Reply::SendFailed(e) => write!(f, "failed to send: {}", e),
+        }
+    }
+}
+
+impl LookupTrace {
+    pub fn new(target: SHA, key: Option<String>) -> Self {
+        Self {
+            target,
+            key,
+            queries: Vec::new(),
+            closest: Vec::new(),
+            stop: StopReason::Exhausted,
+            took: Duration::ZERO,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let queries: Vec<Value> = self
+            .queries
+            .iter()
+            .map(|query| {
+                json!({
+                    "node": contact_json(&query.node),
+                    "round": query.round,
+                    "sent_ms": query.sent.as_millis(),
+                    "answered_ms": query.answered.map(|answered| answered.as_millis()),
+                    "reply": reply_json(&query.reply),
+                })
+            })
+            .collect();
+        json!({
+            "target": self.target.to_hex(),
+            "key": self.key,
+            "queries": queries,
+            "closest": self.closest.iter().map(contact_json).collect::<Vec<_>>(),
+            "stop": self.stop.name(),
+            "took_ms": self.took.as_millis(),
+        })
+    }
+}
+
+fn reply_json(reply: &Reply) -> Value {
+    match reply {
+        Reply::Nodes { count, new } => json!({ "type": "nodes", "count": count, "new": new }),
+        Reply::Record { seq } => json!({ "type": "record", "seq": seq }),
+        Reply::InvalidRecord => json!({ "type": "invalid_record" }),
+        Reply::Tombstone => json!({ "type": "tombstone" }),
+        Reply::Timeout => json!({ "type": "timeout" }),
+        Reply::SendFailed(e) => json!({ "type": "send_failed", "error": e }),
+    }
+}
+
+impl fmt::Display for LookupTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "lookup of {} ({})", key, self.target.to_hex())?,
+            None => write!(f, "lookup of node {}", self.target.to_hex())?,
+        }
+        write!(
+            f,
+            ": {} queries in {} ms, stopped: {}",
+            self.queries.len(),
+            self.took.as_millis(),
+            self.stop
+        )?;
+        for (index, query) in self.queries.iter().enumerate() {
+            write!(
+                f,
+                "\n  {:>3}. round {} +{} ms {} {}:{} {}",
+                index + 1,
+                query.round,
+                query.sent.as_millis(),
+                query.node.node_id.to_hex(),
+                query.node.ip_address,
+                query.node.port,
+                query.reply
+            )?;
+            if let Some(answered) = query.answered {
+                write!(f, " in {} ms", answered.as_millis())?;
+            }
+        }
+        if !self.closest.is_empty() {
+            write!(f, "\nclosest nodes:")?;
+        }
+        for contact in &self.closest {
+            write!(
+                f,
+                "\n  {} {}:{}",
+                contact.node_id.to_hex(),
+                contact.ip_address,
+                contact.port
+            )?;
+        }
+        Ok(())
+    }
+}
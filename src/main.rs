@@ -1,41 +1,185 @@
 use std::{
-    io::{self, BufRead},
-    sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
-    },
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
     thread,
 };
 
 use clap::*;
 use kademlia::{
-    cli::{self},
+    chunking,
+    cli::{self, StorageBackend},
+    client,
+    config::{self, LIST_PAGE_SIZE},
+    control,
+    data_dir::DataDir,
+    export::{self, ExportFormat},
+    identity::PublicKey,
     logError, logInfo, logWarn,
+    logging::{self, ConsoleLogging, FileLogging},
+    memory_storage::MemoryStorage,
     node::Node,
-    storage::{SqlLiteStorage, Storage},
+    runtime::{NodeHandle, Shutdown},
+    sha::SHA,
+    storage::{KeyRange, SqlLiteStorage, Storage, StorageResult},
 };
 
-fn main() {
-    let args = cli::Cli::parse();
-    let node_arc = Arc::new(Mutex::new(Node::new(&args)));
-    let shutdown = Arc::new(AtomicBool::new(false));
+#[cfg(feature = "http")]
+use kademlia::gateway;
+#[cfg(feature = "sled")]
+use kademlia::sled_storage::SledStorage;
 
-    let handle = thread::spawn({
-        let node_clone = Arc::clone(&node_arc);
-        let shutdown_clone = Arc::clone(&shutdown);
-        move || {
-            Node::listen(node_clone, shutdown_clone);
+fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+    if let Err(e) = setup_logging(&cli) {
+        logError!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    let config = match config::Config::load(cli.config.as_deref(), &cli.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            logError!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let cli::Commands::Config {
+        command: cli::ConfigCommand::Show,
+    } = cli.command
+    {
+        print!("{}", config.to_toml());
+        return ExitCode::SUCCESS;
+    }
+    config::install(config).unwrap();
+
+    let (args, socket) = match cli.command {
+        cli::Commands::Init(args) => (args, None),
+        cli::Commands::Daemon { node, socket } => (node, Some(socket)),
+        command @ cli::Commands::Ctl { .. } => return client::ctl(&command),
+        command => return client::run(&command),
+    };
+    #[cfg(not(feature = "http"))]
+    if args.http.is_some() || args.metrics.is_some() {
+        logError!("This binary was built without the http feature");
+        return ExitCode::FAILURE;
+    }
+    let data_dir = match DataDir::from_args(&args) {
+        Ok(data_dir) => data_dir,
+        Err(e) => {
+            logError!("Failed to open the data directory: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let socket = socket.map(|socket| socket.unwrap_or_else(|| data_dir.socket_path()));
+    match args.storage {
+        StorageBackend::Sqlite => {
+            let storage = open_storage(SqlLiteStorage::new(data_dir.sqlite_path()));
+            let node = Node::with_storage(&args, data_dir, storage);
+            run(node, socket, &args)
+        }
+        StorageBackend::Memory => {
+            let node = Node::with_storage(&args, data_dir, MemoryStorage::new());
+            run(node, socket, &args)
+        }
+        #[cfg(feature = "sled")]
+        StorageBackend::Sled => {
+            let storage = open_storage(SledStorage::new(data_dir.sled_path()));
+            let node = Node::with_storage(&args, data_dir, storage);
+            run(node, socket, &args)
+        }
+        #[cfg(not(feature = "sled"))]
+        StorageBackend::Sled => {
+            logError!("This binary was built without the sled feature");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// installs the filter and the sink picked with the --log-* options
+fn setup_logging(cli: &cli::Cli) -> Result<(), String> {
+    if let Some(spec) = cli.log_level.clone().or_else(|| env::var("KADE_LOG").ok()) {
+        logging::set_filter(spec.parse()?);
+    }
+    match &cli.log_file {
+        Some(path) => {
+            let sink =
+                FileLogging::open(path, cli.log_file_max_bytes, cli.log_files, cli.log_format)
+                    .map_err(|e| {
+                        format!("Failed to open the log file {}: {}", path.display(), e)
+                    })?;
+            logging::set_sink(Box::new(sink));
+        }
+        None => logging::set_sink(Box::new(ConsoleLogging::new(cli.log_format))),
+    }
+    Ok(())
+}
+
+fn open_storage<T>(storage: StorageResult<T>) -> T {
+    storage.unwrap_or_else(|e| {
+        logError!("Failed to open the database: {}", e.message);
+        std::process::exit(1);
+    })
+}
+
+// a daemon serves its control socket where a node started with init reads stdin, the HTTP
+// gateway and the metrics run next to either. The node runs until the shutdown is requested
+// by close, the control socket, or SIGINT/SIGTERM
+fn run<T: Storage + Send + 'static>(
+    node: Node<T>,
+    socket: Option<PathBuf>,
+    args: &cli::InitArgs,
+) -> ExitCode {
+    let mut handle = NodeHandle::start(node, args.hand_off_on_shutdown);
+    if let Err(e) = handle.shutdown_on_signals() {
+        logError!("Failed to install the signal handlers: {}", e);
+    }
+    #[cfg(feature = "http")]
+    if let Some(address) = args.http.clone() {
+        handle.spawn(move |node, shutdown| {
+            if let Err(e) = gateway::serve(&node, &address, &shutdown) {
+                logError!("Failed to serve the HTTP gateway on {}: {}", address, e);
+            }
+        });
+    }
+    #[cfg(feature = "http")]
+    if let Some(address) = args.metrics.clone() {
+        handle.spawn(move |node, shutdown| {
+            if let Err(e) = gateway::serve_metrics(&node, &address, &shutdown) {
+                logError!("Failed to serve the metrics on {}: {}", address, e);
+            }
+        });
+    }
+    match socket {
+        Some(socket) => handle.spawn(move |node, shutdown| {
+            if let Err(e) = control::serve(&node, &socket, &shutdown) {
+                logError!(
+                    "Failed to serve the control socket {}: {}",
+                    socket.display(),
+                    e
+                );
+                shutdown.request();
+            }
+        }),
+        // a read from stdin can't be interrupted, so this thread isn't waited for, and stdin
+        // closing leaves the node running
+        None => {
+            let node = Arc::clone(handle.node());
+            let shutdown = Arc::clone(handle.shutdown_signal());
+            thread::spawn(move || handle_input(node, &shutdown));
+        }
+    }
+    match handle.run_until_shutdown() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            logError!("Failed to save the node's state: {}", e);
+            ExitCode::FAILURE
         }
-    });
-    let node_clone = Arc::clone(&node_arc);
-    handle_input(node_clone, &shutdown);
-    if shutdown.load(Ordering::SeqCst) {
-        return;
     }
-    let _ = handle.join();
 }
 
-fn handle_input(node: Arc<Mutex<Node<SqlLiteStorage>>>, shutdown: &Arc<AtomicBool>) {
+fn handle_input<T: Storage>(node: Arc<Mutex<Node<T>>>, shutdown: &Shutdown) {
     let stdin = io::stdin();
     for line in stdin.lock().lines() {
         let input = line.unwrap();
@@ -50,27 +194,184 @@ fn handle_input(node: Arc<Mutex<Node<SqlLiteStorage>>>, shutdown: &Arc<AtomicBoo
                     .send_ping(address.to_owned().to_owned());
             }
             ["store", key, value] => {
-                // store it locally for now, it shouldn't be done like that in kademlia
-                // implementation
-                let _ = node
+                match node
                     .lock()
                     .unwrap()
-                    .store((*key).to_string(), (*value).to_string());
-                logInfo!("stored the pair ({}, {})", key, value);
+                    .store((*key).to_string(), (*value).to_string())
+                {
+                    Ok(report) => logInfo!("{}", report),
+                    Err(e) => logError!("Failed to store the pair ({}, {}): {}", key, value, e),
+                }
+            }
+            ["store", key, value, min_replicas] => {
+                let Ok(min_replicas) = min_replicas.parse::<usize>() else {
+                    logWarn!("Usage: store <key> <value> [min_replicas]");
+                    continue;
+                };
+                match node.lock().unwrap().store_with_min_replicas(
+                    (*key).to_string(),
+                    (*value).to_string(),
+                    min_replicas,
+                ) {
+                    Ok(report) => logInfo!("{}", report),
+                    Err(e) => logError!("Failed to store the pair ({}, {}): {}", key, value, e),
+                }
             }
-            ["get", key] => {
-                match node.lock().unwrap().get_value((*key).to_string()) {
+            ["store_coded", key, value, rest @ ..] => {
+                let shards = match rest {
+                    [] => Some((
+                        config::get().records.erasure_data_shards,
+                        config::get().records.erasure_parity_shards,
+                    )),
+                    [data_shards, parity_shards] => {
+                        data_shards.parse().ok().zip(parity_shards.parse().ok())
+                    }
+                    _ => None,
+                };
+                let Some((data_shards, parity_shards)) = shards else {
+                    logWarn!("Usage: store_coded <key> <value> [data_shards parity_shards]");
+                    continue;
+                };
+                match node.lock().unwrap().store_erasure_coded(
+                    (*key).to_string(),
+                    (*value).to_string(),
+                    data_shards,
+                    parity_shards,
+                ) {
+                    Ok(report) => logInfo!("{}", report),
+                    Err(e) => logError!("Failed to store the pair ({}, {}): {}", key, value, e),
+                }
+            }
+            ["get", key] => match node.lock().unwrap().get_value((*key).to_string()) {
+                Some(value) => logInfo!("Found value: {}", value),
+                None => logInfo!("couldn't find a value for this key"),
+            },
+            ["trace_get", key] => {
+                let (value, trace) = node.lock().unwrap().get_value_traced((*key).to_string());
+                logInfo!("{}", trace);
+                match value {
                     Some(value) => logInfo!("Found value: {}", value),
                     None => logInfo!("couldn't find a value for this key"),
                 }
+            }
+            ["store_mutable", salt, value, rest @ ..] => {
+                let cas = match rest {
+                    [] => None,
+                    [cas] => match cas.parse::<u64>() {
+                        Ok(cas) => Some(cas),
+                        Err(_) => {
+                            logWarn!("Usage: store_mutable <salt> <value> [cas]");
+                            continue;
+                        }
+                    },
+                    _ => {
+                        logWarn!("Usage: store_mutable <salt> <value> [cas]");
+                        continue;
+                    }
+                };
+                match node.lock().unwrap().put_mutable(
+                    (*salt).to_string(),
+                    (*value).to_string(),
+                    cas,
+                ) {
+                    Ok(report) => logInfo!("{}", report),
+                    Err(e) => logError!("Failed to store the mutable record {}: {}", salt, e),
+                }
+            }
+            ["get_mutable", public_key, salt] => {
+                let Some(public_key) = PublicKey::from_hex(public_key) else {
+                    logWarn!("Invalid public key, expected 64 hex characters");
+                    continue;
+                };
+                match node.lock().unwrap().get_mutable(&public_key, salt) {
+                    Some(record) => logInfo!(
+                        "Found value: {} (seq {})",
+                        record.value,
+                        record.seq().unwrap_or_default()
+                    ),
+                    None => logInfo!("couldn't find a value for this key"),
+                }
+            }
+            ["store_immutable", value] => {
+                match node.lock().unwrap().put_immutable(value.as_bytes()) {
+                    Ok((content_hash, report)) => {
+                        logInfo!("{}", report);
+                        logInfo!("Stored under hash {}", content_hash.to_hex());
+                    }
+                    Err(e) => logError!("Failed to store the content: {}", e),
+                }
+            }
+            ["get_immutable", content_hash] => {
+                let Some(content_hash) = SHA::from_hex(content_hash) else {
+                    logWarn!("Invalid hash, expected 40 hex characters");
+                    continue;
+                };
+                match node.lock().unwrap().get_immutable(&content_hash) {
+                    Some(content) => {
+                        logInfo!("Found value: {}", String::from_utf8_lossy(&content))
+                    }
+                    None => logInfo!("couldn't find a value for this key"),
+                }
+            }
+            ["put_file", path] => match chunking::put_file(&node, Path::new(path)) {
+                Ok((manifest_hash, report)) => {
+                    logInfo!("{}", report);
+                    logInfo!("Stored {} under hash {}", path, manifest_hash.to_hex());
+                }
+                Err(e) => logError!("Failed to store {}: {}", path, e),
             },
+            ["get_file", manifest_hash, rest @ ..] => {
+                let Some(manifest_hash) = SHA::from_hex(manifest_hash) else {
+                    logWarn!("Invalid hash, expected 40 hex characters");
+                    continue;
+                };
+                let output = match rest {
+                    [] => None,
+                    [output] => Some(Path::new(output)),
+                    _ => {
+                        logWarn!("Usage: get_file <hash> [output]");
+                        continue;
+                    }
+                };
+                match chunking::get_file(&node, &manifest_hash, output) {
+                    Ok((output, report)) => {
+                        logInfo!("{}", report);
+                        logInfo!("Saved the file to {}", output.display());
+                    }
+                    Err(e) => logError!("Failed to fetch the file: {}", e),
+                }
+            }
+            ["provide", key] => match node.lock().unwrap().announce_provider((*key).to_string()) {
+                Ok(report) => logInfo!("{}", report),
+                Err(e) => logError!("Failed to announce ourselves as provider of {}: {}", key, e),
+            },
+            ["providers", key] => {
+                let providers = node.lock().unwrap().get_providers((*key).to_string());
+                logInfo!("Found {} providers for {}:", providers.len(), key);
+                for contact in providers {
+                    logInfo!("IP: {}, Port: {}", contact.ip_address, contact.port);
+                }
+            }
             ["close"] => {
-                shutdown.store(true, Ordering::SeqCst);
+                shutdown.request();
                 return;
             }
-            ["delete", key] => {
-                let _ = node.lock().unwrap().storage.remove(key);
+            ["close", "handoff"] => {
+                shutdown.request_with_hand_off();
+                return;
             }
+            ["log_level"] => logInfo!("Log filter: {}", logging::filter()),
+            ["log_level", spec] => match spec.parse() {
+                Ok(filter) => {
+                    logging::set_filter(filter);
+                    logInfo!("Log filter: {}", logging::filter());
+                }
+                Err(e) => logWarn!("{}", e),
+            },
+            ["delete", key] => match node.lock().unwrap().delete((*key).to_string()) {
+                Ok(report) => logInfo!("{}", report),
+                Err(e) => logError!("Failed to delete the key {}: {}", key, e),
+            },
             ["routing_table_nodes"] => {
                 let rt = &node.lock().unwrap().routing_table;
                 logInfo!("Routing table nodes:");
@@ -80,16 +381,127 @@ fn handle_input(node: Arc<Mutex<Node<SqlLiteStorage>>>, shutdown: &Arc<AtomicBoo
                     }
                 }
             }
-            ["list"] => match node.lock().unwrap().storage.list_all() {
-                Ok(pairs) => {
-                    for (key, value) in pairs {
-                        logInfo!("Key: {}, Value: {}", key, value);
+            ["export", path, rest @ ..] => {
+                let format = match rest {
+                    [] => ExportFormat::JsonLines,
+                    [format] => match format.parse() {
+                        Ok(format) => format,
+                        Err(e) => {
+                            logWarn!("{}", e);
+                            continue;
+                        }
+                    },
+                    _ => {
+                        logWarn!("Usage: export <path> [jsonl|binary]");
+                        continue;
+                    }
+                };
+                let result = File::create(path).and_then(|file| {
+                    export::export(&node.lock().unwrap().storage, BufWriter::new(file), format)
+                });
+                match result {
+                    Ok(count) => logInfo!("Exported {} entries to {}", count, path),
+                    Err(e) => logError!("Failed to export to {}: {}", path, e),
+                }
+            }
+            ["import", path] => {
+                let result = File::open(path).and_then(|file| {
+                    export::import(&node.lock().unwrap().storage, BufReader::new(file))
+                });
+                match result {
+                    Ok(report) => logInfo!("{} from {}", report, path),
+                    Err(e) => logError!("Failed to import {}: {}", path, e),
+                }
+            }
+            ["list", rest @ ..] => {
+                let range = match rest {
+                    [] => KeyRange::default(),
+                    [prefix] => KeyRange::prefix(prefix),
+                    _ => {
+                        logWarn!("Usage: list [prefix]");
+                        continue;
+                    }
+                };
+                list(&node, &range);
+            }
+            ["list_range", start, end] => {
+                let range = KeyRange {
+                    start: Some((*start).to_string()),
+                    end: Some((*end).to_string()),
+                    ..KeyRange::default()
+                };
+                list(&node, &range);
+            }
+            ["count", rest @ ..] => {
+                let range = match rest {
+                    [] => KeyRange::default(),
+                    [prefix] => KeyRange::prefix(prefix),
+                    _ => {
+                        logWarn!("Usage: count [prefix]");
+                        continue;
+                    }
+                };
+                match node.lock().unwrap().storage.count(&range) {
+                    Ok(count) => logInfo!("{} keys", count),
+                    Err(e) => logError!("Database error occurred: {}", e.message),
+                }
+            }
+            ["sync"] => match node.lock().unwrap().sync_with_neighbours() {
+                Ok(peers) => logInfo!("Started anti-entropy with {} neighbours", peers),
+                Err(e) => logError!("Failed to start anti-entropy: {}", e),
+            },
+            ["verify"] => match node.lock().unwrap().verify_and_repair() {
+                Ok((report, repaired)) => {
+                    for error in &report.integrity_errors {
+                        logError!("Integrity check: {}", error);
                     }
+                    logInfo!(
+                        "{}, {}/{} fetched again",
+                        report,
+                        repaired,
+                        report.quarantined.len()
+                    );
                 }
+                Err(e) => logError!("Failed to verify the storage: {}", e),
+            },
+            ["stats"] => match node.lock().unwrap().storage.stats() {
+                Ok(stats) => logInfo!("{}", stats),
                 Err(e) => logError!("Database error occurred: {}", e.message),
             },
             _ => {
-                logWarn!("Unknown command. Available commands: ping, store, get, delete, close");
+                logWarn!(
+                    "Unknown command. Available commands: ping, store, store_coded, get, trace_get, \
+                     store_mutable, get_mutable, store_immutable, get_immutable, put_file, \
+                     get_file, provide, providers, delete, list, list_range, count, verify, sync, \
+                     stats, export, import, routing_table_nodes, log_level, close"
+                );
+            }
+        }
+    }
+}
+
+// prints the pairs in range a page at a time, taking the node lock only while loading a page
+fn list<T: Storage>(node: &Arc<Mutex<Node<T>>>, range: &KeyRange) {
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = node
+            .lock()
+            .unwrap()
+            .storage
+            .list(range, cursor.as_deref(), LIST_PAGE_SIZE);
+        match page {
+            Ok(page) => {
+                for (key, value) in page.entries {
+                    logInfo!("Key: {}, Value: {}", key, value);
+                }
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    return;
+                }
+            }
+            Err(e) => {
+                logError!("Database error occurred: {}", e.message);
+                return;
             }
         }
     }
@@ -0,0 +1,226 @@
+use crate::{
+    config,
+    contact::Contact,
+    logDebug,
+    record::{Record, Tombstone},
+    storage::{KeyRange, Page, Storage, StorageError, StorageResult, StorageStats},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{Mutex, MutexGuard},
+};
+
+// keeps everything in memory, for tests and ephemeral nodes: nothing survives a restart
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    // the value, and the record when it was stored signed
+    data: BTreeMap<String, (String, Option<Record>)>,
+    tombstones: BTreeMap<String, Tombstone>,
+    // providers of each key with their expiry
+    providers: HashMap<String, Vec<(Contact, i64)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn range<'a, V>(
+        data: &'a BTreeMap<String, V>,
+        range: &KeyRange,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = (&'a String, &'a V)> {
+        let (lower, upper) = range.key_bounds(after);
+        // BTreeMap::range panics on an empty range rather than returning nothing
+        let empty = match (&lower, &upper) {
+            (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper)) => {
+                lower >= upper
+            }
+            _ => false,
+        };
+        let (lower, upper) = if empty {
+            (Bound::Unbounded, Bound::Unbounded)
+        } else {
+            (lower, upper)
+        };
+        data.range::<String, _>((lower, upper))
+            .take(if empty { 0 } else { usize::MAX })
+    }
+
+    fn state(&self) -> StorageResult<MutexGuard<'_, MemoryState>> {
+        self.state.lock().map_err(|e| StorageError {
+            message: e.to_string(),
+        })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn print(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn store(&self, key: &str, value: &String) -> StorageResult<()> {
+        self.state()?
+            .data
+            .insert(key.to_string(), (value.clone(), None));
+        logDebug!("inserted successfully");
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Option<String>> {
+        Ok(self.state()?.data.get(key).map(|(value, _)| value.clone()))
+    }
+
+    fn remove(&self, key: &str) -> StorageResult<()> {
+        if self.state()?.data.remove(key).is_some() {
+            logDebug!("deleted the pair of key : {} ", key);
+        } else {
+            logDebug!("no such key");
+        }
+        Ok(())
+    }
+
+    fn contains(&self, key: &str) -> StorageResult<bool> {
+        Ok(self.state()?.data.contains_key(key))
+    }
+
+    fn list(
+        &self,
+        range: &KeyRange,
+        after: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<Page<String>> {
+        let state = self.state()?;
+        let entries = Self::range(&state.data, range, after)
+            .take(limit)
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect();
+        Ok(Page::new(entries, limit))
+    }
+
+    fn count(&self, range: &KeyRange) -> StorageResult<usize> {
+        Ok(Self::range(&self.state()?.data, range, None).count())
+    }
+
+    fn stats(&self) -> StorageResult<StorageStats> {
+        let state = self.state()?;
+        Ok(StorageStats {
+            entries: state.data.len(),
+            records: state
+                .data
+                .values()
+                .filter(|(_, record)| record.is_some())
+                .count(),
+            value_bytes: state
+                .data
+                .values()
+                .map(|(value, _)| value.len() as u64)
+                .sum(),
+            tombstones: state.tombstones.len(),
+            providers: state.providers.values().map(Vec::len).sum(),
+        })
+    }
+
+    fn for_each_entry(
+        &self,
+        visit: &mut dyn FnMut(String, String, Option<Record>) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        for (key, (value, record)) in self.state()?.data.iter() {
+            visit(key.clone(), value.clone(), record.clone())?;
+        }
+        Ok(())
+    }
+
+    fn store_record(&self, record: &Record) -> StorageResult<()> {
+        self.state()?.data.insert(
+            record.key.clone(),
+            (record.value.clone(), Some(record.clone())),
+        );
+        logDebug!("inserted record for key {} successfully", record.key);
+        Ok(())
+    }
+
+    fn get_record(&self, key: &str) -> StorageResult<Option<Record>> {
+        Ok(self
+            .state()?
+            .data
+            .get(key)
+            .and_then(|(_, record)| record.clone()))
+    }
+
+    fn store_tombstone(&self, tombstone: &Tombstone) -> StorageResult<()> {
+        let mut state = self.state()?;
+        state
+            .tombstones
+            .insert(tombstone.key.clone(), tombstone.clone());
+        state.data.remove(&tombstone.key);
+        logDebug!("stored tombstone for key {}", tombstone.key);
+        Ok(())
+    }
+
+    fn get_tombstone(&self, key: &str) -> StorageResult<Option<Tombstone>> {
+        Ok(self.state()?.tombstones.get(key).cloned())
+    }
+
+    fn remove_tombstone(&self, key: &str) -> StorageResult<()> {
+        self.state()?.tombstones.remove(key);
+        Ok(())
+    }
+
+    fn list_tombstones(&self) -> StorageResult<Vec<Tombstone>> {
+        Ok(self.state()?.tombstones.values().cloned().collect())
+    }
+
+    fn remove_expired_tombstones(&self, now: i64) -> StorageResult<usize> {
+        let mut state = self.state()?;
+        let before = state.tombstones.len();
+        state
+            .tombstones
+            .retain(|_, tombstone| tombstone.expires_at > now);
+        Ok(before - state.tombstones.len())
+    }
+
+    fn add_provider(&self, key: &str, provider: &Contact, expires_at: i64) -> StorageResult<()> {
+        let mut state = self.state()?;
+        let providers = state.providers.entry(key.to_string()).or_default();
+        providers.retain(|(contact, _)| contact.node_id != provider.node_id);
+        providers.push((*provider, expires_at));
+        // keep the ones furthest from expiring
+        providers.sort_by_key(|(_, expires_at)| std::cmp::Reverse(*expires_at));
+        providers.truncate(config::get().records.max_providers_per_key);
+        Ok(())
+    }
+
+    fn get_providers(&self, key: &str, now: i64) -> StorageResult<Vec<Contact>> {
+        Ok(self
+            .state()?
+            .providers
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|(_, expires_at)| *expires_at > now)
+                    .map(|(contact, _)| *contact)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove_expired_providers(&self, now: i64) -> StorageResult<usize> {
+        let mut state = self.state()?;
+        let mut removed = 0;
+        for providers in state.providers.values_mut() {
+            let before = providers.len();
+            providers.retain(|(_, expires_at)| *expires_at > now);
+            removed += before - providers.len();
+        }
+        state.providers.retain(|_, providers| !providers.is_empty());
+        Ok(removed)
+    }
+}
@@ -1,125 +1,750 @@
+use crate::anti_entropy::{self, RangeDigest};
+use crate::config;
 use crate::contact::Contact;
+use crate::record::{Record, RecordKind, Tombstone};
 use crate::sha::SHA;
 use crate::storage::Storage;
+use crate::token::Token;
 use crate::{
-    logError, logInfo,
+    logDebug, logError, logInfo, logWarn,
     network::{Message, MessageType},
     node::Node,
-    storage::SqlLiteStorage,
 };
+use std::collections::HashMap;
 use std::io::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
-pub fn handle_incoming_message(node: &mut Node<SqlLiteStorage>, message: &Message) -> Result<()> {
+// source is the address the packet really came from, unlike the sender it declares
+pub fn handle_incoming_message<T: Storage>(
+    node: &mut Node<T>,
+    message: &Message,
+    source: SocketAddr,
+) -> Result<()> {
     let target = message.sender;
-    node.routing_table.insert_node(&target);
+    // anti-entropy is answered at the address the packet came from, it sends a lot back
+    let peer = Contact {
+        ip_address: source.ip(),
+        port: source.port(),
+        ..target
+    };
+    let source = source.ip();
+    if !message.read_only && node.routing_table.insert_node(&target) {
+        node.queue_handoff(target);
+    }
 
     match &message.message_type {
         MessageType::Ping => handle_ping(node, target),
-        MessageType::Store { key, value } => handle_store(node, key, value),
+        MessageType::Store { record, cas, token } => {
+            handle_store(node, target, source, record, *cas, token)
+        }
+        MessageType::Delete { tombstone, token } => {
+            handle_delete(node, target, source, tombstone, token)
+        }
         MessageType::Pong => handle_pong(target),
         MessageType::FindNode { wanted_id } => handle_find_node(node, target, wanted_id),
         MessageType::FindValue { key } => handle_find_value(node, target, key),
-        MessageType::FindNodeResponse { nodes: _ } => {
-            logInfo!("Received FIND_NODE_RESPONSE - handled by iterative lookup");
+        MessageType::AddProvider { key, token } => {
+            handle_add_provider(node, target, source, key, token)
+        }
+        MessageType::GetProviders { key } => handle_get_providers(node, target, key),
+        MessageType::GetProvidersResponse { .. } => {
+            logDebug!("Received GET_PROVIDERS_RESPONSE - handled by provider lookup");
             Ok(())
         }
-        MessageType::FindValueResponse { value: _, nodes: _ } => {
-            logInfo!("Received FIND_VALUE_RESPONSE - handled by iterative lookup");
+        MessageType::FindNodeResponse { .. } => {
+            logDebug!("Received FIND_NODE_RESPONSE - handled by iterative lookup");
+            Ok(())
+        }
+        MessageType::FindValueResponse { .. } => {
+            logDebug!("Received FIND_VALUE_RESPONSE - handled by iterative lookup");
+            Ok(())
+        }
+        MessageType::SyncDigest {
+            ranges,
+            token,
+            reply_token,
+        } => handle_sync_digest(node, peer, ranges, token, reply_token),
+        MessageType::SyncKeys {
+            range,
+            after,
+            until,
+            entries,
+            token,
+        } => handle_sync_keys(
+            node,
+            peer,
+            *range,
+            (after.as_deref(), until.as_deref()),
+            entries,
+            token,
+        ),
+        MessageType::SyncPull { keys } => handle_sync_pull(node, peer, keys),
+        MessageType::StoreResponse { key: _, error: _ } => {
+            logDebug!("Received STORE_RESPONSE - handled by store");
             Ok(())
         }
     }
 }
 
-fn handle_ping(node: &mut Node<SqlLiteStorage>, target: Contact) -> Result<()> {
-    logInfo!("Received PING from {}:{}", target.ip_address, target.port);
+fn handle_ping<T: Storage>(node: &mut Node<T>, target: Contact) -> Result<()> {
+    logDebug!("Received PING from {}:{}", target.ip_address, target.port);
     node.send_pong(target)?;
     Ok(())
 }
 
-fn handle_store(node: &mut Node<SqlLiteStorage>, key: &String, value: &String) -> Result<()> {
-    logInfo!(
+fn handle_store<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    source: IpAddr,
+    record: &Record,
+    cas: Option<u64>,
+    token: &Token,
+) -> Result<()> {
+    logDebug!(
         "Received STORE from {}:{} for key: {}",
-        node.contact.ip_address,
-        node.contact.port,
+        target.ip_address,
+        target.port,
+        record.key
+    );
+    // always answer, the publisher counts our ack towards its replication factor
+    let checked = check_write_token(node, &source, token)
+        .and_then(|()| check_store(&node.storage, record, cas));
+    let error = match checked {
+        Ok(true) => match node.storage.store_record(record) {
+            Ok(()) => None,
+            Err(e) => {
+                logError!("DB Error: {}", e.message);
+                Some(e.message)
+            }
+        },
+        // we already hold this content
+        Ok(false) => None,
+        Err(reason) => {
+            logWarn!("Rejected STORE for key {}: {}", record.key, reason);
+            Some(reason)
+        }
+    };
+    send_store_response(node, target, &record.key, error)
+}
+
+// a record is accepted only if it's signed by its publisher, its key matches its kind (for
+// immutable ones: the value hashes to the key), the key isn't owned by another publisher,
+// and it isn't older than what we hold or than its publisher's tombstone
+// returns whether the record should be stored
+pub fn check_store<T: Storage>(
+    storage: &T,
+    record: &Record,
+    cas: Option<u64>,
+) -> std::result::Result<bool, String> {
+    if !record.verify() {
+        return Err("invalid signature or key".to_string());
+    }
+
+    let tombstone = storage.get_tombstone(&record.key).map_err(|e| e.message)?;
+    if let Some(tombstone) = tombstone
+        && !tombstone.is_expired()
+    {
+        if tombstone.covers(record) {
+            return Err("key was deleted by its publisher".to_string());
+        }
+        // the record was published after the tombstone (or by someone else once the key
+        // was freed), so the tombstone no longer applies
+        storage
+            .remove_tombstone(&record.key)
+            .map_err(|e| e.message)?;
+    }
+
+    let existing = storage.get_record(&record.key).map_err(|e| e.message)?;
+    if let Some(expected) = cas {
+        let current = existing.as_ref().and_then(|existing| existing.seq());
+        if current != Some(expected) {
+            return Err(format!(
+                "compare-and-swap failed: expected sequence number {}, stored {:?}",
+                expected, current
+            ));
+        }
+    }
+    if let Some(existing) = existing {
+        // the same content may be published by anyone, the first publisher keeps owning it
+        if existing.kind == RecordKind::Immutable && existing.value == record.value {
+            return Ok(false);
+        }
+        if existing.publisher != record.publisher {
+            return Err("key is owned by another publisher".to_string());
+        }
+        match (existing.seq(), record.seq()) {
+            // republishing the very same version is fine
+            (Some(_), Some(_)) if existing == *record => {}
+            (Some(stored_seq), Some(seq)) if seq <= stored_seq => {
+                return Err(format!(
+                    "stale sequence number {}, {} is stored",
+                    seq, stored_seq
+                ));
+            }
+            (None, None) if existing.published_at > record.published_at => {
+                return Err("a newer version of the record is stored".to_string());
+            }
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+fn handle_delete<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    source: IpAddr,
+    tombstone: &Tombstone,
+    token: &Token,
+) -> Result<()> {
+    logDebug!(
+        "Received DELETE from {}:{} for key: {}",
+        target.ip_address,
+        target.port,
+        tombstone.key
+    );
+    let checked = check_write_token(node, &source, token)
+        .and_then(|()| check_delete(&node.storage, tombstone));
+    let error = match checked {
+        Ok(true) => match node.storage.store_tombstone(tombstone) {
+            Ok(()) => None,
+            Err(e) => {
+                logError!("DB Error: {}", e.message);
+                Some(e.message)
+            }
+        },
+        // we already hold this tombstone or a later one
+        Ok(false) => None,
+        Err(reason) => {
+            logWarn!("Rejected DELETE for key {}: {}", tombstone.key, reason);
+            Some(reason)
+        }
+    };
+    send_store_response(node, target, &tombstone.key, error)
+}
+
+// only the publisher of the stored record may delete it,
+// returns whether the tombstone should be stored
+pub fn check_delete<T: Storage>(
+    storage: &T,
+    tombstone: &Tombstone,
+) -> std::result::Result<bool, String> {
+    if !tombstone.verify() {
+        return Err("invalid signature".to_string());
+    }
+    if tombstone.is_expired() {
+        return Err("tombstone has expired".to_string());
+    }
+
+    if let Some(existing) = storage.get_record(&tombstone.key).map_err(|e| e.message)? {
+        if existing.publisher != tombstone.publisher {
+            return Err("only the publisher of the key can delete it".to_string());
+        }
+        if !tombstone.covers(&existing) {
+            return Err("the stored record is newer than the tombstone".to_string());
+        }
+    } else if storage.contains(&tombstone.key).map_err(|e| e.message)? {
+        return Err("key was stored without a publisher".to_string());
+    }
+
+    let current = storage
+        .get_tombstone(&tombstone.key)
+        .map_err(|e| e.message)?;
+    Ok(!matches!(current, Some(current) if current.deleted_at >= tombstone.deleted_at))
+}
+
+// writes are only accepted from addresses we recently answered a lookup from, checked
+// against the address the packet came from, so that off-path attackers can't inject data
+// with a forged sender
+fn check_write_token<T: Storage>(
+    node: &Node<T>,
+    source: &IpAddr,
+    token: &Token,
+) -> std::result::Result<(), String> {
+    if node.write_tokens.validate(source, token) {
+        Ok(())
+    } else {
+        Err("invalid or expired write token".to_string())
+    }
+}
+
+fn send_store_response<T: Storage>(
+    node: &Node<T>,
+    target: Contact,
+    key: &str,
+    error: Option<String>,
+) -> Result<()> {
+    node.send(
+        target.ip_address.to_string(),
+        target.port,
+        MessageType::StoreResponse {
+            key: key.to_string(),
+            error,
+        },
+    )
+}
+
+// the provider is always the sender, nobody can announce someone else
+fn handle_add_provider<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    source: IpAddr,
+    key: &str,
+    token: &Token,
+) -> Result<()> {
+    logDebug!(
+        "Received ADD_PROVIDER from {}:{} for key: {}",
+        target.ip_address,
+        target.port,
         key
     );
-    node.storage.store(key, value)?;
-    Ok(())
+    let expires_at =
+        chrono::Utc::now().timestamp_millis() + config::get().records.provider_ttl_secs * 1000;
+    let error = match check_write_token(node, &source, token) {
+        Ok(()) => match node.storage.add_provider(key, &target, expires_at) {
+            Ok(()) => None,
+            Err(e) => {
+                logError!("DB Error: {}", e.message);
+                Some(e.message)
+            }
+        },
+        Err(reason) => {
+            logWarn!("Rejected ADD_PROVIDER for key {}: {}", key, reason);
+            Some(reason)
+        }
+    };
+    send_store_response(node, target, key, error)
+}
+
+fn handle_get_providers<T: Storage>(node: &mut Node<T>, target: Contact, key: &str) -> Result<()> {
+    logDebug!(
+        "Received GET_PROVIDERS for key {} from {}:{}",
+        key,
+        target.ip_address,
+        target.port
+    );
+    let now = chrono::Utc::now().timestamp_millis();
+    let providers = node.storage.get_providers(key, now).unwrap_or_else(|e| {
+        logError!("DB Error: {}", e.message);
+        Vec::new()
+    });
+    let key_id = SHA::hash_string(&key.to_string());
+    let closest_nodes = node.routing_table.find_k_nearest_nodes(key_id);
+    logDebug!(
+        "Sending {} providers and {} closest nodes back",
+        providers.len(),
+        closest_nodes.len()
+    );
+    node.send(
+        target.ip_address.to_string(),
+        target.port,
+        MessageType::GetProvidersResponse {
+            key: key.to_string(),
+            providers,
+            nodes: closest_nodes,
+            token: node.write_tokens.issue(&target.ip_address),
+        },
+    )
 }
 
 fn handle_pong(target: Contact) -> Result<()> {
-    logInfo!("Received PONG from {}:{}", target.ip_address, target.port);
+    logDebug!("Received PONG from {}:{}", target.ip_address, target.port);
     Ok(())
 }
 
-fn handle_find_node(
-    node: &mut Node<SqlLiteStorage>,
+fn handle_find_node<T: Storage>(
+    node: &mut Node<T>,
     target: Contact,
     wanted_id: &SHA,
 ) -> Result<()> {
-    logInfo!(
+    logDebug!(
         "Received FIND_NODE for ID {:?} from {}:{}",
         wanted_id,
         target.ip_address,
         target.port
     );
     let closest_nodes = node.routing_table.find_k_nearest_nodes(*wanted_id);
-    logInfo!("Sending {} closest nodes back", closest_nodes.len());
+    logDebug!("Sending {} closest nodes back", closest_nodes.len());
 
     node.send(
         target.ip_address.to_string(),
         target.port,
         MessageType::FindNodeResponse {
             nodes: closest_nodes,
+            token: node.write_tokens.issue(&target.ip_address),
         },
     )
 }
 
-fn handle_find_value(node: &mut Node<SqlLiteStorage>, target: Contact, key: &String) -> Result<()> {
-    logInfo!(
+fn handle_find_value<T: Storage>(node: &mut Node<T>, target: Contact, key: &String) -> Result<()> {
+    logDebug!(
         "Received FIND_VALUE for key {} from {}:{}",
         key,
         target.ip_address,
         target.port
     );
 
-    match node.storage.get(key) {
-        Ok(Some(value)) => {
-            logInfo!("Found value locally, sending it back");
-            node.send(
-                target.ip_address.to_string(),
-                target.port,
-                MessageType::FindValueResponse {
-                    value: Some(value),
-                    nodes: Vec::new(),
-                },
-            )
+    let key_id = crate::sha::SHA::hash_string(key);
+
+    // a deleted key answers with its tombstone, which hides the copies left on other nodes,
+    // along with the record we hold: readers only take a tombstone against a record of its
+    // publisher's, see ValueLookup::handle_answer
+    let record = node.storage.get_record(key).unwrap_or_else(|e| {
+        logError!("DB Error: {}", e.message);
+        None
+    });
+    let tombstone = node
+        .storage
+        .get_tombstone(key)
+        .ok()
+        .flatten()
+        .filter(|tombstone| !tombstone.is_expired());
+    // a newer version of a mutable record might be elsewhere, and so might the record a
+    // tombstone deletes, so the reader gets nodes to carry on with
+    let nodes = match (&record, &tombstone) {
+        (Some(record), None) if record.seq().is_none() => Vec::new(),
+        _ => node.routing_table.find_k_nearest_nodes(key_id),
+    };
+    logDebug!(
+        "Sending back {} record, {} tombstone and {} nodes",
+        if record.is_some() { "the" } else { "no" },
+        if tombstone.is_some() { "the" } else { "no" },
+        nodes.len()
+    );
+    node.send(
+        target.ip_address.to_string(),
+        target.port,
+        MessageType::FindValueResponse {
+            key: key.clone(),
+            record,
+            nodes,
+            tombstone,
+            token: node.write_tokens.issue(&target.ip_address),
+        },
+    )
+}
+
+// answers every range whose digest differs from ours with our keys in it, split over as many
+// SYNC_KEYS as needed, each one saying which part of the range it covers. Every round scans our
+// storage, so it takes a write token and a peer only gets one every
+// maintenance.sync_min_round_interval_secs
+fn handle_sync_digest<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    ranges: &[RangeDigest],
+    token: &Token,
+    reply_token: &Token,
+) -> Result<()> {
+    logDebug!(
+        "Received SYNC_DIGEST from {}:{}",
+        target.ip_address,
+        target.port
+    );
+    if let Err(reason) = check_write_token(node, &target.ip_address, token) {
+        logWarn!(
+            "Rejected SYNC_DIGEST from {}:{}: {}",
+            target.ip_address,
+            target.port,
+            reason
+        );
+        return Ok(());
+    }
+    let interval = Duration::from_secs(config::get().maintenance.sync_min_round_interval_secs);
+    node.sync_answered.retain(|_, at| at.elapsed() < interval);
+    if node.sync_answered.contains_key(&target.ip_address) {
+        logDebug!(
+            "Ignoring SYNC_DIGEST from {}:{}, it started a round less than {}s ago",
+            target.ip_address,
+            target.port,
+            interval.as_secs()
+        );
+        return Ok(());
+    }
+    node.sync_answered.insert(target.ip_address, Instant::now());
+    node.remember_write_token(&target, *reply_token);
+    let ours = anti_entropy::shared_entries(node, &target)?;
+    for digest in anti_entropy::digests(&ours) {
+        if ranges.contains(&digest) {
+            continue;
         }
-        Ok(None) => {
-            logInfo!("Value not found locally, sending k closest nodes");
-            let key_id = crate::sha::SHA::hash_string(key);
-            let closest_nodes = node.routing_table.find_k_nearest_nodes(key_id);
+        let entries: Vec<(String, SHA)> = ours
+            .get(&digest.range)
+            .map(|entries| entries.iter().map(|(k, v)| (k.clone(), *v)).collect())
+            .unwrap_or_default();
+        let chunks: Vec<&[(String, SHA)]> = if entries.is_empty() {
+            vec![&[]]
+        } else {
+            entries
+                .chunks(config::get().maintenance.sync_max_keys_per_message)
+                .collect()
+        };
+        let mut after: Option<String> = None;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let until = match chunk.last() {
+                Some((key, _)) if i + 1 < chunks.len() => Some(key.clone()),
+                _ => None,
+            };
             node.send(
                 target.ip_address.to_string(),
                 target.port,
-                MessageType::FindValueResponse {
-                    value: None,
-                    nodes: closest_nodes,
+                MessageType::SyncKeys {
+                    range: digest.range,
+                    after: after.clone(),
+                    until: until.clone(),
+                    entries: chunk.to_vec(),
+                    token: node.write_tokens.issue(&target.ip_address),
                 },
-            )
+            )?;
+            after = until;
         }
-        Err(e) => {
-            logError!("DB Error: {}", e.message);
-            let key_id = crate::sha::SHA::hash_string(key);
-            let closest_nodes = node.routing_table.find_k_nearest_nodes(key_id);
-            node.send(
-                target.ip_address.to_string(),
-                target.port,
-                MessageType::FindValueResponse {
-                    value: None,
-                    nodes: closest_nodes,
-                },
-            )
+    }
+    Ok(())
+}
+
+// compares the peer's keys with ours over the part of the range they cover: we send what the
+// peer is missing or holds another version of, and ask for what we are missing the same way
+fn handle_sync_keys<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    range: u8,
+    (after, until): (Option<&str>, Option<&str>),
+    entries: &[(String, SHA)],
+    token: &Token,
+) -> Result<()> {
+    logDebug!(
+        "Received SYNC_KEYS for range {} with {} keys from {}:{}",
+        range,
+        entries.len(),
+        target.ip_address,
+        target.port
+    );
+    let interval = Duration::from_secs(config::get().maintenance.sync_min_round_interval_secs);
+    let asked = node
+        .sync_started
+        .lock()
+        .unwrap()
+        .get(&target.ip_address)
+        .is_some_and(|at| at.elapsed() < interval);
+    if !asked {
+        logWarn!(
+            "Ignoring SYNC_KEYS from {}:{}, we didn't start a round with it",
+            target.ip_address,
+            target.port
+        );
+        return Ok(());
+    }
+    node.remember_write_token(&target, *token);
+    let covered =
+        |key: &str| after.is_none_or(|after| key > after) && until.is_none_or(|until| key <= until);
+    let ours = anti_entropy::shared_entries(node, &target)?
+        .remove(&range)
+        .unwrap_or_default();
+    let theirs: HashMap<&str, &SHA> = entries.iter().map(|(k, v)| (k.as_str(), v)).collect();
+
+    let to_send: Vec<&String> = ours
+        .iter()
+        .filter(|(key, version)| covered(key) && theirs.get(key.as_str()) != Some(version))
+        .map(|(key, _)| key)
+        .take(config::get().maintenance.sync_max_transfers)
+        .collect();
+    for key in to_send {
+        if let Some(record) = node.storage.get_record(key)? {
+            node.send_store(record, None, vec![(target, *token)])?;
+        }
+    }
+
+    let to_pull: Vec<String> = entries
+        .iter()
+        .filter(|(key, version)| covered(key) && ours.get(key) != Some(version))
+        .map(|(key, _)| key.clone())
+        .take(config::get().maintenance.sync_max_transfers)
+        .collect();
+    if !to_pull.is_empty() {
+        logInfo!(
+            "Pulling {} records from {}:{}",
+            to_pull.len(),
+            target.ip_address,
+            target.port
+        );
+        node.send(
+            target.ip_address.to_string(),
+            target.port,
+            MessageType::SyncPull { keys: to_pull },
+        )?;
+    }
+    Ok(())
+}
+
+// sends the records the peer asked for, with the token it gave us in its SYNC_DIGEST
+fn handle_sync_pull<T: Storage>(
+    node: &mut Node<T>,
+    target: Contact,
+    keys: &[String],
+) -> Result<()> {
+    logDebug!(
+        "Received SYNC_PULL for {} keys from {}:{}",
+        keys.len(),
+        target.ip_address,
+        target.port
+    );
+    let Some(token) = node.fresh_write_token(&target) else {
+        logWarn!(
+            "No write token from {}:{}, ignoring SYNC_PULL",
+            target.ip_address,
+            target.port
+        );
+        return Ok(());
+    };
+    for key in keys
+        .iter()
+        .take(config::get().maintenance.sync_max_transfers)
+    {
+        if let Some(record) = node.storage.get_record(key)? {
+            node.send_store(record, None, vec![(target, token)])?;
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::memory_storage::MemoryStorage;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn store_rejects_a_bad_signature() {
+        let storage = MemoryStorage::new();
+        let mut record = Record::new(&Identity::generate(), "key".to_string(), "a".to_string());
+        record.value = "b".to_string();
+        assert!(check_store(&storage, &record, None).is_err());
+    }
+
+    #[test]
+    fn store_rejects_another_publisher() {
+        let storage = MemoryStorage::new();
+        let owner = Record::new(&Identity::generate(), "key".to_string(), "a".to_string());
+        storage.store_record(&owner).unwrap();
+        let other = Record::new(&Identity::generate(), "key".to_string(), "b".to_string());
+        assert!(check_store(&storage, &other, None).is_err());
+    }
+
+    #[test]
+    fn store_respects_its_publishers_tombstone() {
+        let storage = MemoryStorage::new();
+        let identity = Identity::generate();
+        let record = Record::new(&identity, "key".to_string(), "a".to_string());
+        thread::sleep(Duration::from_millis(2));
+        let tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        storage.store_tombstone(&tombstone).unwrap();
+        assert!(check_store(&storage, &record, None).is_err());
+
+        // published again after the deletion
+        thread::sleep(Duration::from_millis(2));
+        let record = Record::new(&identity, "key".to_string(), "b".to_string());
+        assert_eq!(check_store(&storage, &record, None), Ok(true));
+        assert_eq!(storage.get_tombstone("key").unwrap(), None);
+    }
+
+    #[test]
+    fn store_rejects_a_stale_seq() {
+        let storage = MemoryStorage::new();
+        let identity = Identity::generate();
+        let salt = "salt".to_string();
+        let stored = Record::new_mutable(&identity, salt.clone(), 2, "b".to_string());
+        storage.store_record(&stored).unwrap();
+        let older = Record::new_mutable(&identity, salt.clone(), 1, "a".to_string());
+        assert!(check_store(&storage, &older, None).is_err());
+        let same = Record::new_mutable(&identity, salt.clone(), 2, "c".to_string());
+        assert!(check_store(&storage, &same, None).is_err());
+        // republishing the stored version is fine
+        assert_eq!(check_store(&storage, &stored, None), Ok(true));
+        let newer = Record::new_mutable(&identity, salt, 3, "c".to_string());
+        assert_eq!(check_store(&storage, &newer, None), Ok(true));
+    }
+
+    #[test]
+    fn store_checks_the_expected_seq() {
+        let storage = MemoryStorage::new();
+        let identity = Identity::generate();
+        let salt = "salt".to_string();
+        let first = Record::new_mutable(&identity, salt.clone(), 1, "a".to_string());
+        // nothing is stored yet, so no sequence number can match
+        assert!(check_store(&storage, &first, Some(0)).is_err());
+        storage.store_record(&first).unwrap();
+
+        let second = Record::new_mutable(&identity, salt, 2, "b".to_string());
+        assert!(check_store(&storage, &second, Some(0)).is_err());
+        assert!(check_store(&storage, &second, Some(2)).is_err());
+        assert_eq!(check_store(&storage, &second, Some(1)), Ok(true));
+    }
+
+    #[test]
+    fn store_keeps_the_first_publisher_of_immutable_content() {
+        let storage = MemoryStorage::new();
+        let first = Record::new_immutable(&Identity::generate(), b"content");
+        storage.store_record(&first).unwrap();
+        let again = Record::new_immutable(&Identity::generate(), b"content");
+        assert_eq!(check_store(&storage, &again, None), Ok(false));
+    }
+
+    #[test]
+    fn delete_rejects_a_bad_signature() {
+        let storage = MemoryStorage::new();
+        let mut tombstone = Tombstone::new(&Identity::generate(), "key".to_string(), 60);
+        tombstone.key = "other".to_string();
+        assert!(check_delete(&storage, &tombstone).is_err());
+    }
+
+    #[test]
+    fn delete_rejects_an_expired_tombstone() {
+        let storage = MemoryStorage::new();
+        let tombstone = Tombstone::new(&Identity::generate(), "key".to_string(), -1);
+        assert!(check_delete(&storage, &tombstone).is_err());
+    }
+
+    #[test]
+    fn delete_rejects_another_publisher() {
+        let storage = MemoryStorage::new();
+        let record = Record::new(&Identity::generate(), "key".to_string(), "a".to_string());
+        storage.store_record(&record).unwrap();
+        thread::sleep(Duration::from_millis(2));
+        let tombstone = Tombstone::new(&Identity::generate(), "key".to_string(), 60);
+        assert!(check_delete(&storage, &tombstone).is_err());
+    }
+
+    #[test]
+    fn delete_rejects_a_tombstone_older_than_the_record() {
+        let storage = MemoryStorage::new();
+        let identity = Identity::generate();
+        let tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        thread::sleep(Duration::from_millis(2));
+        let record = Record::new(&identity, "key".to_string(), "a".to_string());
+        storage.store_record(&record).unwrap();
+        assert!(check_delete(&storage, &tombstone).is_err());
+    }
+
+    #[test]
+    fn delete_accepts_the_publisher_once() {
+        let storage = MemoryStorage::new();
+        let identity = Identity::generate();
+        let record = Record::new(&identity, "key".to_string(), "a".to_string());
+        storage.store_record(&record).unwrap();
+        thread::sleep(Duration::from_millis(2));
+        let tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        assert_eq!(check_delete(&storage, &tombstone), Ok(true));
+        storage.store_tombstone(&tombstone).unwrap();
+        // the same tombstone again changes nothing
+        assert_eq!(check_delete(&storage, &tombstone), Ok(false));
+    }
+
+    #[test]
+    fn delete_rejects_a_key_stored_without_a_publisher() {
+        let storage = MemoryStorage::new();
+        storage.store("key", &"a".to_string()).unwrap();
+        let tombstone = Tombstone::new(&Identity::generate(), "key".to_string(), 60);
+        assert!(check_delete(&storage, &tombstone).is_err());
+    }
 }
@@ -0,0 +1,260 @@
+// What the node has been doing, as counters and histograms shared by the whole process, and
+// render() to write them, with the routing table and storage sizes read at that moment, in the
+// Prometheus text format. The HTTP gateway serves them on /metrics, see gateway.rs
+use crate::{node::Node, storage::Storage};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+// a counter per combination of label values
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], count: u64) {
+        if count == 0 {
+            return;
+        }
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        *self.values.lock().unwrap().entry(labels).or_default() += count;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (values, count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                labels(self.labels, values),
+                count
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    // how many observations fell in each bucket, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+// a histogram per combination of label values
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    // upper bounds, ascending
+    bounds: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Samples>>,
+}
+
+impl Histogram {
+    const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        bounds: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            bounds,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], value: f64) {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let samples = values.entry(labels).or_insert_with(|| Samples {
+            buckets: vec![0; self.bounds.len()],
+            ..Samples::default()
+        });
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            samples.buckets[bucket] += 1;
+        }
+        samples.sum += value;
+        samples.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let bucket_names = [self.labels, &["le"]].concat();
+        for (values, samples) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            let bounds = self.bounds.iter().map(|bound| bound.to_string());
+            let counts = samples.buckets.iter().map(|count| {
+                cumulative += count;
+                cumulative
+            });
+            // the +Inf bucket holds everything
+            let buckets = bounds
+                .zip(counts)
+                .chain([("+Inf".to_string(), samples.count)]);
+            for (bound, count) in buckets {
+                let mut bucket_values = values.clone();
+                bucket_values.push(bound);
+                let bucket = labels(&bucket_names, &bucket_values);
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, bucket, count);
+            }
+            let values = labels(self.labels, values);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, values, samples.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, values, samples.count);
+        }
+    }
+}
+
+pub static MESSAGES_SENT: Counter = Counter::new(
+    "kade_messages_sent_total",
+    "Messages sent, by type",
+    &["type"],
+);
+pub static MESSAGES_RECEIVED: Counter = Counter::new(
+    "kade_messages_received_total",
+    "Messages received, by type",
+    &["type"],
+);
+// undecodable, receive_error or send_error
+pub static PACKETS_DROPPED: Counter = Counter::new(
+    "kade_packets_dropped_total",
+    "Packets that were dropped, by reason",
+    &["reason"],
+);
+// by the request that went unanswered, the acks of deletes and provider announcements
+// count as store
+pub static TIMEOUTS: Counter = Counter::new(
+    "kade_timeouts_total",
+    "Requests that got no answer in time, by request",
+    &["request"],
+);
+// kind is nodes or value, result is found or not_found
+pub static LOOKUPS: Counter = Counter::new(
+    "kade_lookups_total",
+    "Iterative lookups, by kind and result",
+    &["kind", "result"],
+);
+pub static LOOKUP_SECONDS: Histogram = Histogram::new(
+    "kade_lookup_duration_seconds",
+    "How long iterative lookups took",
+    &["kind"],
+    &[
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ],
+);
+pub static LOOKUP_HOPS: Histogram = Histogram::new(
+    "kade_lookup_hops",
+    "How many rounds of queries iterative lookups took",
+    &["kind"],
+    &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0],
+);
+
+// records one finished lookup
+pub fn lookup(kind: &str, found: bool, took: Duration, hops: usize) {
+    let result = if found { "found" } else { "not_found" };
+    LOOKUPS.inc(&[kind, result]);
+    LOOKUP_SECONDS.observe(&[kind], took.as_secs_f64());
+    LOOKUP_HOPS.observe(&[kind], hops as f64);
+}
+
+// everything in the Prometheus text format, the gauges are read from the node now
+pub fn render<T: Storage>(node: &Node<T>) -> String {
+    let mut out = String::new();
+    for counter in [
+        &MESSAGES_SENT,
+        &MESSAGES_RECEIVED,
+        &PACKETS_DROPPED,
+        &TIMEOUTS,
+        &LOOKUPS,
+    ] {
+        counter.render(&mut out);
+    }
+    for histogram in [&LOOKUP_SECONDS, &LOOKUP_HOPS] {
+        histogram.render(&mut out);
+    }
+
+    let name = "kade_routing_table_contacts";
+    header(&mut out, name, "Contacts in each non-empty bucket", "gauge");
+    for (index, bucket) in node.routing_table.buckets.iter().enumerate() {
+        if !bucket.nodes.is_empty() {
+            let bucket_label = labels(&["bucket"], &[index.to_string()]);
+            let _ = writeln!(out, "{}{} {}", name, bucket_label, bucket.nodes.len());
+        }
+    }
+
+    if let Ok(stats) = node.storage.stats() {
+        for (name, help, value) in [
+            (
+                "kade_storage_entries",
+                "Entries stored",
+                stats.entries as u64,
+            ),
+            (
+                "kade_storage_records",
+                "Entries stored as signed records",
+                stats.records as u64,
+            ),
+            (
+                "kade_storage_value_bytes",
+                "Bytes of stored values",
+                stats.value_bytes,
+            ),
+            (
+                "kade_storage_tombstones",
+                "Tombstones stored",
+                stats.tombstones as u64,
+            ),
+            (
+                "kade_storage_providers",
+                "Provider records stored",
+                stats.providers as u64,
+            ),
+        ] {
+            header(&mut out, name, help, "gauge");
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// {name="value",...}, nothing without labels
+fn labels(names: &[&str], values: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
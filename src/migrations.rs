@@ -0,0 +1,216 @@
+use crate::{
+    logInfo,
+    storage::{StorageError, StorageResult},
+};
+use rusqlite::{Connection, Transaction, params};
+
+type Migration = fn(&Transaction) -> StorageResult<()>;
+
+// the schema version of a database is PRAGMA user_version, which is the number of these that
+// were applied to it. Only ever append here, a released migration must never change
+const MIGRATIONS: &[Migration] = &[
+    create_data_table,
+    add_record_signature_columns,
+    create_tombstones_and_providers,
+    add_record_metadata_columns,
+    create_quarantine,
+];
+
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+// brings the database up to SCHEMA_VERSION, every migration runs in its own transaction
+// together with the version bump, so a failed upgrade leaves the previous version intact
+pub fn migrate(conn: &mut Connection) -> StorageResult<()> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(StorageError {
+            message: format!(
+                "the database has schema version {} but this binary only knows up to version {}, \
+                 upgrade kade to open it",
+                version, SCHEMA_VERSION
+            ),
+        });
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        logInfo!("Migrated the database to schema version {}", index + 1);
+    }
+    Ok(())
+}
+
+pub fn schema_version(conn: &Connection) -> StorageResult<usize> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+// the migrations up to 3 describe the schema that existed before versioning, databases from
+// back then are at version 0 but may already have some of it, hence IF NOT EXISTS and
+// add_column_if_missing there
+
+fn create_data_table(tx: &Transaction) -> StorageResult<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS data (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_record_signature_columns(tx: &Transaction) -> StorageResult<()> {
+    add_column_if_missing(tx, "data", "publisher", "BLOB")?;
+    add_column_if_missing(tx, "data", "published_at", "INTEGER")?;
+    add_column_if_missing(tx, "data", "signature", "BLOB")?;
+    // JSON of the RecordKind, NULL for records signed before there were other kinds
+    add_column_if_missing(tx, "data", "kind", "TEXT")?;
+    Ok(())
+}
+
+fn create_tombstones_and_providers(tx: &Transaction) -> StorageResult<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tombstones (
+            key TEXT PRIMARY KEY,
+            publisher BLOB NOT NULL,
+            deleted_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            signature BLOB NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS providers (
+            key TEXT NOT NULL,
+            node_id BLOB NOT NULL,
+            ip_address TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            PRIMARY KEY (key, node_id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// stored_at is when this node last wrote the row (ms), size is the length of the value in bytes
+fn add_record_metadata_columns(tx: &Transaction) -> StorageResult<()> {
+    tx.execute("ALTER TABLE data ADD COLUMN stored_at INTEGER", [])?;
+    tx.execute("ALTER TABLE data ADD COLUMN size INTEGER", [])?;
+    tx.execute(
+        "UPDATE data SET stored_at = ?1, size = length(CAST(value AS BLOB))",
+        params![chrono::Utc::now().timestamp_millis()],
+    )?;
+    Ok(())
+}
+
+// damaged data rows moved aside by verify, with why and when (ms)
+fn create_quarantine(tx: &Transaction) -> StorageResult<()> {
+    tx.execute(
+        "CREATE TABLE quarantine (
+            key TEXT PRIMARY KEY,
+            value TEXT,
+            publisher BLOB,
+            published_at INTEGER,
+            signature BLOB,
+            kind TEXT,
+            reason TEXT NOT NULL,
+            quarantined_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    column_type: &str,
+) -> StorageResult<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, column_type
+            ),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut statement = conn
+            .prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+            .unwrap();
+        statement
+            .query_map([table], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_an_unversioned_database() {
+        // the schema from before versioning, with a row in it
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE data (key TEXT PRIMARY KEY, value TEXT NOT NULL, publisher BLOB);
+             INSERT INTO data (key, value) VALUES ('key', 'value');",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(
+            columns(&conn, "data"),
+            [
+                "key",
+                "value",
+                "publisher",
+                "published_at",
+                "signature",
+                "kind",
+                "stored_at",
+                "size"
+            ]
+        );
+        for table in ["tombstones", "providers", "quarantine"] {
+            assert!(!columns(&conn, table).is_empty(), "{} is missing", table);
+        }
+        let (value, stored_at, size): (String, Option<i64>, i64) = conn
+            .query_row(
+                "SELECT value, stored_at, size FROM data WHERE key = 'key'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(value, "value");
+        assert!(stored_at.is_some());
+        assert_eq!(size, 5);
+
+        // a second run has nothing left to do
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -1,10 +1,21 @@
-use crate::{contact::Contact, logInfo, sha::SHA};
+use crate::{
+    anti_entropy::RangeDigest,
+    contact::Contact,
+    logDebug, logTrace, metrics,
+    record::{Record, Tombstone},
+    sha::SHA,
+    token::Token,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     io::Result,
     net::{SocketAddr, UdpSocket},
-    sync::mpsc::{self, Receiver},
-    thread,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+    },
+    thread::{self, JoinHandle},
 };
 #[derive(Debug)]
 pub struct Network {
@@ -20,41 +31,63 @@ impl Network {
         Ok(Self { socket })
     }
 
+    pub fn local_port(&self) -> Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
     pub fn send(&self, ip_address: &String, port: u16, data: Vec<u8>) -> Result<()> {
         // get the string of the target ip address + port
         let addr = format!("{}:{}", ip_address, port);
-        logInfo!("Sending to {} Data : {:?}", addr, data);
-        self.socket.send_to(&data, addr)?;
+        logDebug!("Sending {} bytes to {}", data.len(), addr);
+        logTrace!("Data : {:?}", data);
+        if let Err(e) = self.socket.send_to(&data, addr) {
+            metrics::PACKETS_DROPPED.inc(&["send_error"]);
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub fn start_listening(&self) -> Receiver<(Message, SocketAddr)> {
+    // the receiving thread stops once shutdown is set, which closes the channel
+    pub fn start_listening(
+        &self,
+        shutdown: Arc<AtomicBool>,
+    ) -> (Receiver<(Message, SocketAddr)>, JoinHandle<()>) {
         let (tx, rx) = mpsc::channel(); // this is a multiple producers - single consumer channel
         // tx is the producing end, and rx is the consuming end
 
         let config = bincode::config::standard();
+        let settings = &crate::config::get().network;
         let socket = self.socket.try_clone().unwrap(); // clone the socket to be used in the thread
 
-        thread::spawn(move || {
-            let mut buf = [0; 1024];
-            loop {
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0; settings.receive_buffer_bytes];
+            while !shutdown.load(Ordering::SeqCst) {
                 match socket.recv_from(&mut buf) {
                     Ok((len, addr)) => {
-                        if let Ok((msg, _consumed)) =
-                            bincode::serde::decode_from_slice::<Message, _>(&buf[0..len], config)
-                        {
-                            let _ = tx.send((msg, addr));
+                        let decoded =
+                            bincode::serde::decode_from_slice::<Message, _>(&buf[0..len], config);
+                        match decoded {
+                            Ok((msg, _consumed)) => {
+                                let _ = tx.send((msg, addr));
+                            }
+                            Err(e) => {
+                                metrics::PACKETS_DROPPED.inc(&["undecodable"]);
+                                logDebug!("Dropped an undecodable packet from {}: {}", addr, e);
+                            }
                         }
                     }
                     Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         // No data received, just continue the loop
-                        thread::sleep(std::time::Duration::from_millis(100));
+                        thread::sleep(settings.poll_interval());
+                    }
+                    Err(e) => {
+                        metrics::PACKETS_DROPPED.inc(&["receive_error"]);
+                        logDebug!("Failed to receive a packet: {}", e);
                     }
-                    _ => {}
                 }
             }
         });
-        rx
+        (rx, handle)
     }
 }
 
@@ -62,15 +95,86 @@ impl Network {
 pub enum MessageType {
     Ping,
     Pong,
-    Store { key: String, value: String },
+    // cas only applies to mutable records: the store is accepted only if the
+    // sequence number currently stored under the key is the expected one
+    Store {
+        record: Record,
+        cas: Option<u64>,
+        token: Token,
+    },
+    // acknowledges both Store and Delete,
+    // error is None when the record was saved, otherwise it carries the reason it was rejected
+    StoreResponse { key: String, error: Option<String> },
+    Delete { tombstone: Tombstone, token: Token },
     FindValue { key: String },
+    // announces the sender as a provider of the key, acknowledged with a StoreResponse
+    AddProvider { key: String, token: Token },
+    GetProviders { key: String },
+    GetProvidersResponse {
+        key: String,
+        providers: Vec<Contact>,
+        nodes: Vec<Contact>,
+        token: Token,
+    },
     FindNode { wanted_id: SHA },
-    FindNodeResponse { nodes: Vec<Contact> },
-    FindValueResponse { value: Option<String>, nodes: Vec<Contact> },
+    // every response to a lookup carries a write token for the requester
+    FindNodeResponse { nodes: Vec<Contact>, token: Token },
+    // key is the one asked for, so a node can look up several keys on the same peer at once
+    FindValueResponse {
+        key: String,
+        record: Option<Record>,
+        nodes: Vec<Contact>,
+        // set when the key was deleted, so the lookup stops instead of finding a stale copy
+        tombstone: Option<Tombstone>,
+        token: Token,
+    },
+    // anti-entropy, see anti_entropy.rs. SyncDigest carries a write token the receiver handed
+    // out, checked like a STORE's, and reply_token for the receiver to write back with.
+    // SyncKeys lists all of the sender's keys in the range with after < key <= until, None
+    // meaning unbounded
+    SyncDigest {
+        ranges: Vec<RangeDigest>,
+        token: Token,
+        reply_token: Token,
+    },
+    SyncKeys {
+        range: u8,
+        after: Option<String>,
+        until: Option<String>,
+        entries: Vec<(String, SHA)>,
+        token: Token,
+    },
+    SyncPull { keys: Vec<String> },
+}
+
+impl MessageType {
+    // the label the metrics count the message under
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Ping => "ping",
+            MessageType::Pong => "pong",
+            MessageType::Store { .. } => "store",
+            MessageType::StoreResponse { .. } => "store_response",
+            MessageType::Delete { .. } => "delete",
+            MessageType::FindValue { .. } => "find_value",
+            MessageType::AddProvider { .. } => "add_provider",
+            MessageType::GetProviders { .. } => "get_providers",
+            MessageType::GetProvidersResponse { .. } => "get_providers_response",
+            MessageType::FindNode { .. } => "find_node",
+            MessageType::FindNodeResponse { .. } => "find_node_response",
+            MessageType::FindValueResponse { .. } => "find_value_response",
+            MessageType::SyncDigest { .. } => "sync_digest",
+            MessageType::SyncKeys { .. } => "sync_keys",
+            MessageType::SyncPull { .. } => "sync_pull",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
     pub message_type: MessageType,
     pub sender: Contact,
+    // set by nodes that only live for one command (see client.rs): they are answered, but
+    // not added to routing tables, where they would linger once gone
+    pub read_only: bool,
 }
@@ -1,27 +1,38 @@
-use crate::cli::Cli;
-use crate::config::ALPHA;
+use crate::anti_entropy;
+use crate::cli::InitArgs;
+use crate::config::{self, ERASURE_MAX_SHARDS};
 use crate::contact::Contact;
+use crate::data_dir::DataDir;
+use crate::erasure;
+use crate::identity::{Identity, PublicKey};
+use crate::logDebug;
 use crate::logError;
 use crate::logInfo;
 use crate::logWarn;
+use crate::lookup_trace::{LookupTrace, Query, Reply, StopReason};
 use crate::message_handler::handle_incoming_message;
+use crate::metrics;
 use crate::network::Message;
 use crate::network::MessageType;
 use crate::network::*;
 use crate::node_metadata::MetaData;
+use crate::record::{Record, RecordKind, Tombstone};
 use crate::routing_table::RoutingTable;
 use crate::sha::SHA;
 use crate::storage::SqlLiteStorage;
-use crate::storage::Storage;
+use crate::storage::{Storage, VerifyReport};
+use crate::store_report::StoreReport;
+use crate::token::{Token, TokenSecret};
 use bincode;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Result;
-use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -29,40 +40,87 @@ use std::time::{Duration, Instant};
 pub struct Node<T: Storage> {
     pub name: String,
     pub contact: Contact,
+    pub identity: Identity,
     pub routing_table: RoutingTable,
     pub storage: T,
     pub network: Network,
     pub response_map: Option<Arc<Mutex<HashMap<String, Message>>>>,
+    // issues the write tokens we hand out, and keeps the ones peers handed us
+    pub write_tokens: TokenSecret,
+    pub peer_tokens: Mutex<HashMap<String, (Token, Instant)>>,
+    pub data_dir: DataDir,
+    // nodes that joined since the last handoff, waiting for the records they should hold
+    pub pending_handoffs: Vec<Contact>,
+    // when each peer last started an anti-entropy round with us, and when we last started one
+    // with each peer, see anti_entropy.rs
+    pub sync_answered: HashMap<IpAddr, Instant>,
+    pub sync_started: Mutex<HashMap<IpAddr, Instant>>,
+    // see Message::read_only
+    pub read_only: bool,
 }
 
 impl Node<SqlLiteStorage> {
-    pub fn new(args: &Cli) -> Self {
+    pub fn new(args: &InitArgs, data_dir: DataDir) -> Self {
+        let storage = SqlLiteStorage::new(data_dir.sqlite_path()).unwrap();
+        Self::with_storage(args, data_dir, storage)
+    }
+}
+
+impl<T: Storage> Node<T> {
+    pub fn with_storage(args: &InitArgs, data_dir: DataDir, storage: T) -> Self {
         // if the metadata file exists, load it
         // else create the node using the cli args and save it to a file
-        let metadata = MetaData::load_or_create(&args).unwrap();
+        let metadata = MetaData::load_or_create(args, &data_dir.metadata_path()).unwrap();
+        Self::from_metadata(metadata, data_dir, storage, false).unwrap()
+    }
+
+    // read_only is for nodes that only live for one command, see Message::read_only
+    pub fn from_metadata(
+        metadata: MetaData,
+        data_dir: DataDir,
+        storage: T,
+        read_only: bool,
+    ) -> Result<Self> {
         let bootstrap_ip = metadata.bootstrap_ip;
         let bootstrap_port = metadata.bootstrap_port;
 
+        // with port 0 the OS picks one, peers have to be told the real one
+        let network = Network::new("127.0.0.1", metadata.port)?;
+        let port = network.local_port()?;
         let mut node = Self {
             name: metadata.name,
             contact: Contact {
                 node_id: metadata.node_id,
                 ip_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), // to be updated
-                port: metadata.port,
+                port,
             },
+            identity: Identity::from_secret(&metadata.secret_key),
             routing_table: RoutingTable::new(metadata.node_id),
-            storage: SqlLiteStorage::new("local_database.sqlite3").unwrap(),
-            network: Network::new("127.0.0.1", metadata.port).unwrap(),
+            storage,
+            network,
             response_map: None,
+            write_tokens: TokenSecret::new(),
+            peer_tokens: Mutex::new(HashMap::new()),
+            data_dir,
+            pending_handoffs: Vec::new(),
+            sync_answered: HashMap::new(),
+            sync_started: Mutex::new(HashMap::new()),
+            read_only,
         };
 
+        // rejoin through the contacts we knew last time, they learn our address from the ping
+        for contact in node.load_routing_table() {
+            node.routing_table.insert_node(&contact);
+            let _ = node.send_ping(format!("{}:{}", contact.ip_address, contact.port));
+        }
+
         if let (Some(ip), Some(port)) = (bootstrap_ip, bootstrap_port) {
             let bootstrap_addr = format!("{}:{}", ip, port);
             // insert the bootstrap node to our routing routing_table
             let ip_address: IpAddr = ip.parse().unwrap();
             let bootstrap_contact = Contact {
                 node_id: SHA::hash_string(&bootstrap_addr),
-                ip_address: ip_address,
+                ip_address,
                 port,
             };
             node.routing_table.insert_node(&bootstrap_contact);
@@ -77,13 +135,62 @@ impl Node<SqlLiteStorage> {
         }
 
         logInfo!(
-            "Node is running! Port:{}, IP:{}, Node_ID:{:?}",
+            "Node is running! Port:{}, IP:{}, Node_ID:{:?}, Public_Key:{}",
             node.contact.port,
             node.contact.ip_address,
-            node.contact.node_id
+            node.contact.node_id,
+            node.identity.public_key()
         );
 
-        node
+        Ok(node)
+    }
+
+    // what a node writes when it shuts down: the routing table, the metadata with the port it
+    // ended up on, so an OS-picked port survives a restart, and whatever storage still buffers
+    pub fn save_state(&self) -> Result<()> {
+        self.save_routing_table()?;
+        let metadata_path = self.data_dir.metadata_path();
+        if metadata_path.exists() {
+            let mut metadata = MetaData::load(&metadata_path)?;
+            metadata.port = self.contact.port;
+            metadata.save(&metadata_path)?;
+        }
+        self.storage.flush()?;
+        Ok(())
+    }
+
+    pub fn save_routing_table(&self) -> Result<()> {
+        let contacts: Vec<Contact> = self
+            .routing_table
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter().copied())
+            .collect();
+        // write then rename, so a crash mid-write never leaves a truncated snapshot
+        let path = self.data_dir.routing_table_path();
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&contacts)?)?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    fn load_routing_table(&self) -> Vec<Contact> {
+        let path = self.data_dir.routing_table_path();
+        let Ok(snapshot) = std::fs::read_to_string(&path) else {
+            return Vec::new();
+        };
+        match serde_json::from_str::<Vec<Contact>>(&snapshot) {
+            Ok(contacts) => {
+                logInfo!("Loaded {} contacts from {}", contacts.len(), path.display());
+                contacts
+                    .into_iter()
+                    .filter(|contact| contact.node_id != self.contact.node_id)
+                    .collect()
+            }
+            Err(e) => {
+                logWarn!("Ignoring unreadable routing table snapshot: {}", e);
+                Vec::new()
+            }
+        }
     }
 
     // this method is to ping another node, given its address as a string "ip:port"
@@ -97,22 +204,47 @@ impl Node<SqlLiteStorage> {
             .parse()
             .unwrap();
 
-        logInfo!("Sending PING to {}:{}", target_ip, target_port);
+        logDebug!("Sending PING to {}:{}", target_ip, target_port);
         self.send(target_ip, target_port, MessageType::Ping)
     }
 
     // this method is to send a STORE request to a target nodes
     // notice it takes a vector of contacts, because we might want to store the
-    // same key-value pair on multiple nodes
-    pub fn send_store(&self, key: String, value: String, targets: Vec<Contact>) -> Result<()> {
-        let message_type = MessageType::Store { key, value };
+    // same key-value pair on multiple nodes, each with the write token it gave us
+    pub fn send_store(
+        &self,
+        record: Record,
+        cas: Option<u64>,
+        targets: Vec<(Contact, Token)>,
+    ) -> Result<()> {
         logInfo!("Storing the pair on {} nodes", targets.len());
-        for target in targets {
-            logInfo!("Sending STORE to {}:{}", target.ip_address, target.port);
+        for (target, token) in targets {
+            logDebug!("Sending STORE to {}:{}", target.ip_address, target.port);
             self.send(
                 target.ip_address.to_string(),
                 target.port,
-                message_type.clone(),
+                MessageType::Store {
+                    record: record.clone(),
+                    cas,
+                    token,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // this method is to send a DELETE (a tombstone) to the nodes holding the key
+    pub fn send_delete(&self, tombstone: Tombstone, targets: Vec<(Contact, Token)>) -> Result<()> {
+        logInfo!("Sending the tombstone to {} nodes", targets.len());
+        for (target, token) in targets {
+            logDebug!("Sending DELETE to {}:{}", target.ip_address, target.port);
+            self.send(
+                target.ip_address.to_string(),
+                target.port,
+                MessageType::Delete {
+                    tombstone: tombstone.clone(),
+                    token,
+                },
             )?;
         }
         Ok(())
@@ -120,7 +252,7 @@ impl Node<SqlLiteStorage> {
 
     // this method is to send a FIND_NODE request to a target node
     pub fn send_find_node(&self, target: Contact, wanted_id: SHA) -> Result<()> {
-        logInfo!(
+        logDebug!(
             "Sending FIND_NODE for ID {:?} to {}:{}",
             wanted_id,
             target.ip_address,
@@ -138,7 +270,7 @@ impl Node<SqlLiteStorage> {
     pub fn send_find_value(&self, key: String, targets: Vec<Contact>) -> Result<()> {
         logInfo!("Finding value for the key on {} nodes", targets.len());
         for target in targets {
-            logInfo!(
+            logDebug!(
                 "Sending FIND_VALUE to {}:{}",
                 target.ip_address,
                 target.port
@@ -152,9 +284,73 @@ impl Node<SqlLiteStorage> {
         Ok(())
     }
 
+    // this method is to announce ourselves as a provider of a key to the nodes responsible for it
+    pub fn send_add_provider(&self, key: String, targets: Vec<(Contact, Token)>) -> Result<()> {
+        logInfo!(
+            "Announcing ourselves as provider to {} nodes",
+            targets.len()
+        );
+        for (target, token) in targets {
+            logDebug!(
+                "Sending ADD_PROVIDER to {}:{}",
+                target.ip_address,
+                target.port
+            );
+            self.send(
+                target.ip_address.to_string(),
+                target.port,
+                MessageType::AddProvider {
+                    key: key.clone(),
+                    token,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // this method is to ask a node for the providers of a key
+    pub fn send_get_providers(&self, key: String, target: Contact) -> Result<()> {
+        logDebug!(
+            "Sending GET_PROVIDERS to {}:{}",
+            target.ip_address,
+            target.port
+        );
+        self.send(
+            target.ip_address.to_string(),
+            target.port,
+            MessageType::GetProviders { key },
+        )
+    }
+
     // this is to reply to a ping with a pong
+    // pings the address and waits for the PONG, returns the contact the node answered with
+    pub fn ping(&self, target_address: SocketAddr, timeout: Duration) -> Result<Option<Contact>> {
+        let target_key = target_address.to_string();
+        // a PONG of an earlier ping may still be waiting
+        if let Some(ref response_map) = self.response_map
+            && let Ok(mut map) = response_map.lock()
+        {
+            map.remove(&target_key);
+        }
+        self.send_ping(target_key.clone())?;
+
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+                && let Some(msg) = map.remove(&target_key)
+                && let MessageType::Pong = msg.message_type
+            {
+                return Ok(Some(msg.sender));
+            }
+            thread::sleep(config::poll_interval());
+        }
+        metrics::TIMEOUTS.inc(&["ping"]);
+        Ok(None)
+    }
+
     pub fn send_pong(&self, target: Contact) -> Result<()> {
-        logInfo!("Sending PONG to {}:{}", target.ip_address, target.port);
+        logDebug!("Sending PONG to {}:{}", target.ip_address, target.port);
         self.send(
             target.ip_address.to_string(),
             target.port,
@@ -169,9 +365,11 @@ impl Node<SqlLiteStorage> {
         target_port: u16,
         message_type: MessageType,
     ) -> Result<()> {
+        metrics::MESSAGES_SENT.inc(&[message_type.name()]);
         let data = Message {
             message_type,
-            sender: self.contact.clone(),
+            sender: self.contact,
+            read_only: self.read_only,
         };
 
         let config = bincode::config::standard();
@@ -181,50 +379,91 @@ impl Node<SqlLiteStorage> {
             .send(&target_ip.parse().unwrap(), target_port, serialized_message)
     }
 
-    pub fn listen(node: Arc<Mutex<Node<SqlLiteStorage>>>, shutdown: Arc<AtomicBool>) {
+    // starts receiving before it returns, so the caller can wait for responses right away,
+    // and handles the messages on a thread of their own. Once shutdown is set it stops
+    // receiving, waits for the messages being handled and returns
+    pub fn spawn_listener(
+        node: &Arc<Mutex<Node<T>>>,
+        shutdown: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()>
+    where
+        T: Send + 'static,
+    {
         let response_map = Arc::new(Mutex::new(HashMap::<String, Message>::new()));
-        {
+        let (rx, receiver) = {
             let mut node_guard = node.lock().unwrap();
             node_guard.response_map = Some(Arc::clone(&response_map));
-        }
-
-        let rx = node.lock().unwrap().network.start_listening();
-
-        for (msg, _addr) in rx {
-            if shutdown.load(Ordering::SeqCst) {
-                logInfo!("shutting down... ");
-                break;
+            node_guard.network.start_listening(shutdown)
+        };
+        let node = Arc::clone(node);
+        thread::spawn(move || {
+            let handlers = Self::listen(node, rx, response_map);
+            let _ = receiver.join();
+            for handler in handlers {
+                let _ = handler.join();
             }
+        })
+    }
 
+    // runs until the receiving thread closes the channel, returns the handlers that may
+    // still be running
+    fn listen(
+        node: Arc<Mutex<Node<T>>>,
+        rx: Receiver<(Message, SocketAddr)>,
+        response_map: Arc<Mutex<HashMap<String, Message>>>,
+    ) -> Vec<thread::JoinHandle<()>>
+    where
+        T: Send + 'static,
+    {
+        let mut handlers: Vec<thread::JoinHandle<()>> = Vec::new();
+        for (msg, source) in rx {
+            metrics::MESSAGES_RECEIVED.inc(&[msg.message_type.name()]);
             // Check if this is a response message that should be routed to iterative lookup
             let is_response = matches!(
                 &msg.message_type,
-                MessageType::FindNodeResponse { .. } | MessageType::FindValueResponse { .. }
+                MessageType::Pong
+                    | MessageType::FindNodeResponse { .. }
+                    | MessageType::FindValueResponse { .. }
+                    | MessageType::StoreResponse { .. }
+                    | MessageType::GetProvidersResponse { .. }
             );
 
             if is_response {
                 // Store response in response map for iterative lookup
-                let addr_key = format!("{}:{}", msg.sender.ip_address, msg.sender.port);
+                // FIND_VALUE answers also carry the key, several lookups may ask the same node
+                let addr_key = match &msg.message_type {
+                    MessageType::FindValueResponse { key, .. } => {
+                        format!("{}:{}/{}", msg.sender.ip_address, msg.sender.port, key)
+                    }
+                    _ => format!("{}:{}", msg.sender.ip_address, msg.sender.port),
+                };
                 if let Ok(mut map) = response_map.lock() {
                     map.insert(addr_key, msg.clone());
                 }
             }
 
-            thread::spawn({
+            handlers.retain(|handler| !handler.is_finished());
+            handlers.push(thread::spawn({
                 let node_clone = Arc::clone(&node);
                 let msg_clone = msg.clone();
                 move || {
                     let mut node = node_clone.lock().unwrap();
-                    let _ = handle_incoming_message(&mut node, &msg_clone);
+                    let _ = handle_incoming_message(&mut node, &msg_clone, source);
                 }
-            });
+            }));
         }
+        handlers
     }
 
     // Iterative lookup algorithm to find k closest nodes to a target ID
-    fn iterative_lookup_nodes(&self, target_id: SHA) -> Vec<Contact> {
-        use crate::config::K;
+    pub fn iterative_lookup_nodes(&self, target_id: SHA) -> Vec<Contact> {
+        self.iterative_lookup_nodes_traced(target_id).0
+    }
 
+    // the same, along with the trace of what it did
+    pub fn iterative_lookup_nodes_traced(&self, target_id: SHA) -> (Vec<Contact>, LookupTrace) {
+        let start = Instant::now();
+        let mut trace = LookupTrace::new(target_id, None);
         let mut closest_nodes: Vec<Contact> = self.routing_table.find_k_nearest_nodes(target_id);
         let mut queried: HashSet<String> = HashSet::new();
         let mut all_seen: HashSet<String> = HashSet::new();
@@ -235,8 +474,9 @@ impl Node<SqlLiteStorage> {
             all_seen.insert(key);
         }
 
-        let max_iterations = 3; // Prevent infinite loops
+        let max_iterations = config::get().protocol.lookup_iterations;
         let mut iteration = 0;
+        trace.stop = StopReason::IterationLimit;
 
         while iteration < max_iterations {
             iteration += 1;
@@ -248,20 +488,33 @@ impl Node<SqlLiteStorage> {
                     let key = format!("{}:{}", node.ip_address, node.port);
                     !queried.contains(&key)
                 })
-                .take(ALPHA)
+                .take(config::get().protocol.alpha)
                 .cloned()
                 .collect();
 
             if to_query.is_empty() {
+                trace.stop = if closest_nodes.is_empty() {
+                    StopReason::NoContacts
+                } else {
+                    StopReason::Exhausted
+                };
                 break; // No more nodes to query
             }
 
-            // Query nodes in parallel
-            let mut responses: Vec<Vec<Contact>> = Vec::new();
+            // Query nodes in parallel, responses are (index of the query in the trace, nodes)
+            let mut responses: Vec<(usize, Vec<Contact>)> = Vec::new();
             for node in &to_query {
                 let key = format!("{}:{}", node.ip_address, node.port);
                 queried.insert(key);
 
+                let sent = Instant::now();
+                trace.queries.push(Query {
+                    node: *node,
+                    round: iteration,
+                    sent: start.elapsed(),
+                    answered: None,
+                    reply: Reply::Timeout,
+                });
                 if let Err(e) = self.send_find_node(*node, target_id) {
                     logWarn!(
                         "Failed to send FIND_NODE to {}:{}: {}",
@@ -269,40 +522,50 @@ impl Node<SqlLiteStorage> {
                         node.port,
                         e
                     );
+                    trace.queries.last_mut().unwrap().reply = Reply::SendFailed(e.to_string());
                     continue;
                 }
 
                 // Wait for response with timeout
-                let response = self.wait_for_find_node_response(node, Duration::from_secs(2));
+                let response = self.wait_for_find_node_response(node, config::response_timeout());
                 if let Some(nodes) = response {
-                    responses.push(nodes);
+                    trace.queries.last_mut().unwrap().answered = Some(sent.elapsed());
+                    responses.push((trace.queries.len() - 1, nodes));
                 }
             }
 
             // Merge responses into closest_nodes
-            for response_nodes in responses {
+            for (query, response_nodes) in responses {
+                let count = response_nodes.len();
+                let mut new = 0;
                 for node in response_nodes {
                     let key = format!("{}:{}", node.ip_address, node.port);
                     if !all_seen.contains(&key) && node.node_id != self.contact.node_id {
                         all_seen.insert(key.clone());
                         closest_nodes.push(node);
+                        new += 1;
                     }
                 }
+                trace.queries[query].reply = Reply::Nodes { count, new };
             }
 
             // Sort by distance to target and keep only k closest
             closest_nodes.sort_by_key(|contact| contact.node_id ^ target_id);
-            if closest_nodes.len() > K {
-                closest_nodes.truncate(K);
+            if closest_nodes.len() > config::get().protocol.k {
+                closest_nodes.truncate(config::get().protocol.k);
             }
 
             // Check if we've converged (no new closer nodes found)
-            if to_query.len() < ALPHA {
+            if to_query.len() < config::get().protocol.alpha {
+                trace.stop = StopReason::Converged;
                 break;
             }
         }
 
-        closest_nodes
+        trace.took = start.elapsed();
+        trace.closest = closest_nodes.clone();
+        metrics::lookup("nodes", !closest_nodes.is_empty(), trace.took, iteration);
+        (closest_nodes, trace)
     }
 
     // Wait for a FindNodeResponse from a specific node
@@ -316,24 +579,639 @@ impl Node<SqlLiteStorage> {
 
         // Poll the response map for a matching response
         while start.elapsed() < timeout {
-            if let Some(ref response_map) = self.response_map {
-                if let Ok(mut map) = response_map.lock() {
-                    if let Some(msg) = map.remove(&target_key) {
-                        if let MessageType::FindNodeResponse { nodes } = msg.message_type {
-                            return Some(nodes);
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+                && let Some(msg) = map.remove(&target_key)
+                && let MessageType::FindNodeResponse { nodes, token } = msg.message_type
+            {
+                self.remember_write_token(target, token);
+                return Some(nodes);
+            }
+            thread::sleep(config::poll_interval());
+        }
+
+        metrics::TIMEOUTS.inc(&["find_node"]);
+        None
+    }
+
+    // Iterative lookups for FindValue, one per key, run side by side: every round each
+    // unfinished lookup queries its next node and all the answers share one deadline
+    // a plain record ends a lookup, but for a mutable one we keep asking the remaining nodes
+    // and keep the valid version with the highest sequence number (starting from `best`).
+    // Returns the finished lookups, with their result and trace
+    fn iterative_lookup_values(&self, mut lookups: Vec<ValueLookup>) -> Vec<ValueLookup> {
+        let start = Instant::now();
+        for lookup in &mut lookups {
+            lookup.closest = self.routing_table.find_k_nearest_nodes(lookup.key_id);
+            for node in &lookup.closest {
+                lookup
+                    .all_seen
+                    .insert(format!("{}:{}", node.ip_address, node.port));
+            }
+        }
+
+        while lookups.iter().any(|lookup| !lookup.done) {
+            let mut in_flight: Vec<(usize, Contact)> = Vec::new();
+            for (index, lookup) in lookups.iter_mut().enumerate() {
+                let Some(node) = lookup.next_target() else {
+                    continue;
+                };
+                lookup.trace.queries.push(Query {
+                    node,
+                    round: lookup.iteration,
+                    sent: start.elapsed(),
+                    answered: None,
+                    reply: Reply::Timeout,
+                });
+                if let Err(e) = self.send_find_value(lookup.key.clone(), vec![node]) {
+                    logWarn!(
+                        "Failed to send FIND_VALUE to {}:{}: {}",
+                        node.ip_address,
+                        node.port,
+                        e
+                    );
+                    lookup.trace.queries.last_mut().unwrap().reply =
+                        Reply::SendFailed(e.to_string());
+                    continue;
+                }
+                in_flight.push((index, node));
+            }
+
+            let queries: Vec<(Contact, &str)> = in_flight
+                .iter()
+                .map(|(index, node)| (*node, lookups[*index].key.as_str()))
+                .collect();
+            let answers = self.wait_for_find_value_responses(&queries, config::response_timeout());
+            for ((index, node), answer) in in_flight.into_iter().zip(answers) {
+                // unanswered queries stay timeouts
+                let Some((answer, answered)) = answer else {
+                    continue;
+                };
+                let lookup = &mut lookups[index];
+                let reply = lookup.handle_answer(&node, self.contact.node_id, answer);
+                let query = lookup.trace.queries.last_mut().unwrap();
+                query.answered = Some(answered);
+                query.reply = reply;
+            }
+            for lookup in lookups.iter_mut().filter(|lookup| lookup.done) {
+                lookup.took.get_or_insert(start.elapsed());
+            }
+        }
+
+        for lookup in &mut lookups {
+            lookup.trace.took = lookup.took.unwrap_or(start.elapsed());
+            lookup.trace.closest = lookup.closest.clone();
+            // a mutable record we held ourselves doesn't make the lookup a success
+            let found = lookup.best_from_network;
+            metrics::lookup("value", found, lookup.trace.took, lookup.iteration);
+        }
+        lookups
+    }
+
+    fn iterative_lookup_value(&self, key: String, best: Option<Record>) -> Option<Record> {
+        self.iterative_lookup_values(vec![ValueLookup::new(key, best)])
+            .pop()
+            .and_then(|lookup| lookup.best)
+    }
+
+    // Wait for the FindValueResponses of several (node, key) queries, sharing one deadline
+    // Returns one ((record, nodes, tombstone), how long it took) per query, in order: record is
+    // Some if found, nodes is the list of closest nodes if value not found and tombstone is
+    // Some if the key was deleted. Queries that got no answer in time get None
+    fn wait_for_find_value_responses(
+        &self,
+        queries: &[(Contact, &str)],
+        timeout: Duration,
+    ) -> Vec<Option<(FindValueAnswer, Duration)>> {
+        let start = Instant::now();
+        let mut answers: Vec<Option<(FindValueAnswer, Duration)>> = vec![None; queries.len()];
+
+        // Poll the response map for the matching responses
+        while start.elapsed() < timeout && answers.iter().any(Option::is_none) {
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+            {
+                for ((target, key), answer) in queries.iter().zip(answers.iter_mut()) {
+                    if answer.is_some() {
+                        continue;
+                    }
+                    let response_key = format!("{}:{}/{}", target.ip_address, target.port, key);
+                    if let Some(msg) = map.remove(&response_key)
+                        && let MessageType::FindValueResponse {
+                            record,
+                            nodes,
+                            tombstone,
+                            token,
+                            ..
+                        } = msg.message_type
+                    {
+                        self.remember_write_token(target, token);
+                        *answer = Some(((record, nodes, tombstone), start.elapsed()));
+                    }
+                }
+            }
+            if answers.iter().any(Option::is_none) {
+                thread::sleep(config::poll_interval());
+            }
+        }
+
+        let unanswered = answers.iter().filter(|answer| answer.is_none()).count();
+        metrics::TIMEOUTS.add(&["find_value"], unanswered as u64);
+        answers
+    }
+
+    // Wait for the STORE acknowledgements of all targets, sharing one deadline
+    // so that storing on k nodes doesn't take k timeouts
+    fn wait_for_store_responses(
+        &self,
+        key: &str,
+        targets: &[Contact],
+        timeout: Duration,
+    ) -> StoreReport {
+        let start = Instant::now();
+        let mut report = StoreReport::new(key.to_string(), targets.to_vec());
+        let mut pending: Vec<Contact> = targets.to_vec();
+
+        while !pending.is_empty() && start.elapsed() < timeout {
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+            {
+                pending.retain(|target| {
+                    let target_key = format!("{}:{}", target.ip_address, target.port);
+                    // only take the entry out if it's the ack we're waiting for,
+                    // it might be a lookup response meant for someone else
+                    let is_ack = matches!(
+                        map.get(&target_key),
+                        Some(Message {
+                            message_type: MessageType::StoreResponse { key: acked_key, .. },
+                            ..
+                        }) if acked_key == key
+                    );
+                    if !is_ack {
+                        return true;
+                    }
+                    if let Some(Message {
+                        message_type: MessageType::StoreResponse { error, .. },
+                        ..
+                    }) = map.remove(&target_key)
+                    {
+                        match error {
+                            None => report.confirmed.push(*target),
+                            Some(reason) => report.rejected.push((*target, reason)),
                         }
                     }
+                    false
+                });
+            }
+            if !pending.is_empty() {
+                thread::sleep(config::poll_interval());
+            }
+        }
+
+        metrics::TIMEOUTS.add(&["store"], pending.len() as u64);
+        report.timed_out = pending;
+        report
+    }
+
+    pub fn store(&self, key: String, value: String) -> Result<StoreReport> {
+        self.store_record(Record::new(&self.identity, key, value), None)
+    }
+
+    // sends an already signed record to the k closest nodes of its key and collects their acks
+    pub fn store_record(&self, record: Record, cas: Option<u64>) -> Result<StoreReport> {
+        let key = record.key.clone();
+        let key_id = SHA::hash_string(&key);
+        // Use iterative lookup to find the actual k-nearest nodes
+        let mut target_nodes = self.iterative_lookup_nodes(key_id);
+        logInfo!("Found {} nodes via iterative lookup", target_nodes.len());
+        target_nodes.truncate(record.replicas());
+        self.replicate(&key, "STORE", target_nodes, |targets| {
+            self.send_store(record, cas, targets)
+        })
+    }
+
+    // runs a write against the targets: gets each one's write token, sends it the write
+    // and collects the acks, the targets we couldn't get a token from count as rejected
+    fn replicate(
+        &self,
+        key: &str,
+        operation: &str,
+        target_nodes: Vec<Contact>,
+        send: impl FnOnce(Vec<(Contact, Token)>) -> Result<()>,
+    ) -> Result<StoreReport> {
+        let (targets, tokenless) =
+            self.collect_write_tokens(target_nodes, config::response_timeout());
+        let contacts: Vec<Contact> = targets.iter().map(|(contact, _)| *contact).collect();
+        send(targets)?;
+
+        let mut report = self.wait_for_store_responses(key, &contacts, config::response_timeout());
+        for contact in tokenless {
+            report.targets.push(contact);
+            report
+                .rejected
+                .push((contact, "no write token".to_string()));
+        }
+        for (contact, reason) in &report.rejected {
+            logWarn!(
+                "{} rejected by {}:{}: {}",
+                operation,
+                contact.ip_address,
+                contact.port,
+                reason
+            );
+        }
+        Ok(report)
+    }
+
+    pub(crate) fn remember_write_token(&self, target: &Contact, token: Token) {
+        let target_key = format!("{}:{}", target.ip_address, target.port);
+        if let Ok(mut tokens) = self.peer_tokens.lock() {
+            tokens.insert(target_key, (token, Instant::now()));
+        }
+    }
+
+    // a token the target handed us that it still accepts
+    pub(crate) fn fresh_write_token(&self, target: &Contact) -> Option<Token> {
+        let target_key = format!("{}:{}", target.ip_address, target.port);
+        let tokens = self.peer_tokens.lock().ok()?;
+        tokens
+            .get(&target_key)
+            .filter(|(_, received_at)| {
+                received_at.elapsed()
+                    < Duration::from_secs(config::get().records.token_rotation_secs)
+            })
+            .map(|(token, _)| *token)
+    }
+
+    // Pairs every target with a write token: the ones we got during the lookup are reused while
+    // they're fresh, the others are asked for one with a FIND_NODE for our own id
+    // Returns (targets with their token, targets that didn't answer in time)
+    fn collect_write_tokens(
+        &self,
+        targets: Vec<Contact>,
+        timeout: Duration,
+    ) -> (Vec<(Contact, Token)>, Vec<Contact>) {
+        let mut pending: Vec<Contact> = Vec::new();
+        for target in &targets {
+            if self.fresh_write_token(target).is_none() {
+                if let Err(e) = self.send_find_node(*target, self.contact.node_id) {
+                    logWarn!(
+                        "Failed to send FIND_NODE to {}:{}: {}",
+                        target.ip_address,
+                        target.port,
+                        e
+                    );
+                    continue;
                 }
+                pending.push(*target);
             }
-            thread::sleep(Duration::from_millis(100));
         }
 
-        None
+        let start = Instant::now();
+        while !pending.is_empty() && start.elapsed() < timeout {
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+            {
+                pending.retain(|target| {
+                    let target_key = format!("{}:{}", target.ip_address, target.port);
+                    if !matches!(
+                        map.get(&target_key),
+                        Some(Message {
+                            message_type: MessageType::FindNodeResponse { .. },
+                            ..
+                        })
+                    ) {
+                        return true;
+                    }
+                    if let Some(Message {
+                        message_type: MessageType::FindNodeResponse { token, .. },
+                        ..
+                    }) = map.remove(&target_key)
+                    {
+                        self.remember_write_token(target, token);
+                    }
+                    false
+                });
+            }
+            if !pending.is_empty() {
+                thread::sleep(config::poll_interval());
+            }
+        }
+        metrics::TIMEOUTS.add(&["find_node"], pending.len() as u64);
+
+        let mut with_tokens = Vec::new();
+        let mut tokenless = Vec::new();
+        for target in targets {
+            match self.fresh_write_token(&target) {
+                Some(token) => with_tokens.push((target, token)),
+                None => tokenless.push(target),
+            }
+        }
+        (with_tokens, tokenless)
+    }
+
+    // publishes a new version of our mutable record under `salt`, with cas set the storage
+    // nodes only accept it if they still hold that sequence number,
+    // without it the next sequence number after the latest one we can find is used
+    pub fn put_mutable(
+        &self,
+        salt: String,
+        value: String,
+        cas: Option<u64>,
+    ) -> Result<StoreReport> {
+        let seq = match cas {
+            Some(expected) => expected + 1,
+            None => {
+                let key = Record::mutable_key(&self.identity.public_key(), &salt);
+                self.get_record(key)
+                    .and_then(|record| record.seq())
+                    .map_or(0, |seq| seq + 1)
+            }
+        };
+        let record = Record::new_mutable(&self.identity, salt, seq, value);
+        self.store_record(record, cas)
+    }
+
+    pub fn get_mutable(&self, publisher: &PublicKey, salt: &str) -> Option<Record> {
+        self.get_record(Record::mutable_key(publisher, salt))
     }
 
-    // Iterative lookup for FindValue
-    fn iterative_lookup_value(&self, key: String) -> Option<String> {
+    // stores content under its own SHA, which is all a reader needs to fetch and verify it
+    pub fn put_immutable(&self, content: &[u8]) -> Result<(SHA, StoreReport)> {
+        let content_hash = SHA::hash(content);
+        let report = self.store_record(Record::new_immutable(&self.identity, content), None)?;
+        Ok((content_hash, report))
+    }
+
+    // the record is verified against the hash by the lookup, whichever peer it came from
+    pub fn get_immutable(&self, content_hash: &SHA) -> Option<Vec<u8>> {
+        self.get_record(Record::immutable_key(content_hash))
+            .and_then(|record| record.content())
+    }
+
+    // same as store, but fails if fewer than min_replicas nodes confirmed saving the pair
+    pub fn store_with_min_replicas(
+        &self,
+        key: String,
+        value: String,
+        min_replicas: usize,
+    ) -> Result<StoreReport> {
+        let report = self.store(key, value)?;
+        if !report.meets(min_replicas) {
+            return Err(std::io::Error::other(format!(
+                "only {} of the required {} replicas confirmed the store",
+                report.replicas(),
+                min_replicas
+            )));
+        }
+        Ok(report)
+    }
+
+    // Deletes a key we published from the whole network: the signed tombstone is sent to the
+    // k closest nodes, and kept locally so that we keep republishing it until it expires
+    pub fn delete(&self, key: String) -> Result<StoreReport> {
+        let tombstone = Tombstone::new(
+            &self.identity,
+            key.clone(),
+            config::get().records.tombstone_ttl_secs,
+        );
+        self.storage.store_tombstone(&tombstone)?;
+
+        let target_nodes = self.iterative_lookup_nodes(SHA::hash_string(&key));
+        logInfo!("Found {} nodes via iterative lookup", target_nodes.len());
+        self.replicate(&key, "DELETE", target_nodes, |targets| {
+            self.send_delete(tombstone, targets)
+        })
+    }
+
+    // checks the local storage, then fetches what it quarantined from the closest nodes again
+    pub fn verify_and_repair(&self) -> Result<(VerifyReport, usize)> {
+        let report = self.storage.verify()?;
+        let mut repaired = 0;
+        for key in &report.quarantined {
+            match self.iterative_lookup_value(key.clone(), None) {
+                Some(record) => {
+                    self.storage.store_record(&record)?;
+                    self.storage.release_quarantined(key)?;
+                    repaired += 1;
+                }
+                None => logWarn!("Couldn't fetch a valid record for {} from the network", key),
+            }
+        }
+        Ok((report, repaired))
+    }
+
+    pub fn queue_handoff(&mut self, newcomer: Contact) {
+        if self.pending_handoffs.len() >= config::get().maintenance.handoff_max_pending {
+            logWarn!(
+                "Too many nodes waiting for a handoff, skipping {}:{}",
+                newcomer.ip_address,
+                newcomer.port
+            );
+            return;
+        }
+        self.pending_handoffs.push(newcomer);
+    }
+
+    // the records a node that just joined should get from us: the ones it is now among the k
+    // closest nodes for (fewer for shards, see Record::replicas). As in the Kademlia paper,
+    // only the node closest to a key among the others sends it, so the newcomer doesn't get
+    // every record from all k of its holders
+    fn handoff_records(&self, newcomer: &Contact) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        self.storage.for_each_entry(&mut |key, _, record| {
+            let Some(record) = record else {
+                return Ok(());
+            };
+            if records.len() >= config::get().maintenance.handoff_max_records {
+                return Ok(());
+            }
+            let key_id = SHA::hash_string(&key);
+            let mut closest = self.routing_table.find_k_nearest_nodes(key_id);
+            closest.truncate(record.replicas());
+            let newcomer_is_close = closest.iter().any(|c| c.node_id == newcomer.node_id);
+            let we_are_closest = closest
+                .iter()
+                .find(|c| c.node_id != newcomer.node_id)
+                .is_none_or(|c| (self.contact.node_id ^ key_id) < (c.node_id ^ key_id));
+            if newcomer_is_close && we_are_closest {
+                records.push(record);
+            }
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    // sends the queued newcomers their records
+    pub fn hand_off_pending(node: &Arc<Mutex<Node<T>>>, shutdown: &AtomicBool) {
+        let newcomers = std::mem::take(&mut node.lock().unwrap().pending_handoffs);
+        for newcomer in newcomers {
+            let records = match node.lock().unwrap().handoff_records(&newcomer) {
+                Ok(records) => records,
+                Err(e) => {
+                    logError!("Failed to pick the records to hand off: {}", e);
+                    continue;
+                }
+            };
+            Self::send_handoff(node, newcomer, records, shutdown);
+        }
+    }
+
+    // before leaving, sends every record we hold to the nodes that should hold it besides us,
+    // at most maintenance.handoff_max_records per node. Returns how many records were sent
+    pub fn hand_off_on_departure(node: &Arc<Mutex<Node<T>>>) -> Result<usize> {
+        let mut plan: HashMap<String, (Contact, Vec<Record>)> = HashMap::new();
+        {
+            let node = node.lock().unwrap();
+            let max_records = config::get().maintenance.handoff_max_records;
+            node.storage.for_each_entry(&mut |key, _, record| {
+                let Some(record) = record else {
+                    return Ok(());
+                };
+                let mut closest = node
+                    .routing_table
+                    .find_k_nearest_nodes(SHA::hash_string(&key));
+                closest.truncate(record.replicas());
+                for contact in closest {
+                    let (_, records) = plan
+                        .entry(format!("{}:{}", contact.ip_address, contact.port))
+                        .or_insert_with(|| (contact, Vec::new()));
+                    if records.len() < max_records {
+                        records.push(record.clone());
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        let mut sent = 0;
+        for (target, records) in plan.into_values() {
+            sent += Self::send_handoff(node, target, records, &AtomicBool::new(false));
+        }
+        Ok(sent)
+    }
+
+    // sends the records to target a batch at a time with the node unlocked in between, so a
+    // big handoff neither floods the link nor holds up message handling. Gives up when cancel
+    // is set, returns how many records were sent
+    fn send_handoff(
+        node: &Arc<Mutex<Node<T>>>,
+        target: Contact,
+        records: Vec<Record>,
+        cancel: &AtomicBool,
+    ) -> usize {
+        if records.is_empty() {
+            return 0;
+        }
+        let (targets, _) = node
+            .lock()
+            .unwrap()
+            .collect_write_tokens(vec![target], config::response_timeout());
+        let Some((_, token)) = targets.into_iter().next() else {
+            logWarn!(
+                "No write token from {}:{}, skipping its handoff",
+                target.ip_address,
+                target.port
+            );
+            return 0;
+        };
+        logInfo!(
+            "Handing {} records over to {}:{}",
+            records.len(),
+            target.ip_address,
+            target.port
+        );
+        let mut sent = 0;
+        for batch in records.chunks(config::get().maintenance.handoff_batch_size) {
+            if cancel.load(Ordering::SeqCst) {
+                break;
+            }
+            {
+                let node = node.lock().unwrap();
+                for record in batch {
+                    match node.send_store(record.clone(), None, vec![(target, token)]) {
+                        Ok(()) => sent += 1,
+                        Err(e) => {
+                            logWarn!("Failed to hand off the record for {}: {}", record.key, e)
+                        }
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(
+                config::get().maintenance.handoff_batch_interval_ms,
+            ));
+        }
+        sent
+    }
+
+    // starts an anti-entropy round with each of our closest neighbours that hands us a write
+    // token, the rest of the exchange happens in the message handlers. Returns how many
+    // neighbours were asked
+    pub fn sync_with_neighbours(&self) -> Result<usize> {
+        let mut peers = self
+            .routing_table
+            .find_k_nearest_nodes(self.contact.node_id);
+        peers.truncate(config::get().maintenance.sync_peers);
+        let (peers, _) = self.collect_write_tokens(peers, config::response_timeout());
+
+        let interval = Duration::from_secs(config::get().maintenance.sync_min_round_interval_secs);
+        let mut started = self.sync_started.lock().unwrap();
+        started.retain(|_, at| at.elapsed() < interval);
+        for (peer, token) in &peers {
+            let ranges = anti_entropy::digests(&anti_entropy::shared_entries(self, peer)?);
+            logDebug!("Sending SYNC_DIGEST to {}:{}", peer.ip_address, peer.port);
+            started.insert(peer.ip_address, Instant::now());
+            self.send(
+                peer.ip_address.to_string(),
+                peer.port,
+                MessageType::SyncDigest {
+                    ranges,
+                    token: *token,
+                    reply_token: self.write_tokens.issue(&peer.ip_address),
+                },
+            )?;
+        }
+        Ok(peers.len())
+    }
+
+    // Sends every live tombstone we hold to the current k closest nodes of its key,
+    // so that nodes that missed the delete or joined since then learn about it
+    pub fn republish_tombstones(&self) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let expired = self.storage.remove_expired_tombstones(now)?;
+        if expired > 0 {
+            logInfo!("Dropped {} expired tombstones", expired);
+        }
+
+        for tombstone in self.storage.list_tombstones()? {
+            let target_nodes = self.iterative_lookup_nodes(SHA::hash_string(&tombstone.key));
+            logInfo!(
+                "Republishing tombstone for key {} to {} nodes",
+                tombstone.key,
+                target_nodes.len()
+            );
+            let (targets, _) = self.collect_write_tokens(target_nodes, config::response_timeout());
+            self.send_delete(tombstone, targets)?;
+        }
+        Ok(())
+    }
+
+    // Tells the k closest nodes of the key that we provide it, they forget it after
+    // PROVIDER_TTL_SECS so providers should announce again before that
+    pub fn announce_provider(&self, key: String) -> Result<StoreReport> {
+        let target_nodes = self.iterative_lookup_nodes(SHA::hash_string(&key));
+        logInfo!("Found {} nodes via iterative lookup", target_nodes.len());
+        self.replicate(&key, "ADD_PROVIDER", target_nodes, |targets| {
+            self.send_add_provider(key.clone(), targets)
+        })
+    }
+
+    // Iterative lookup for GetProviders, collects providers from every node queried
+    // until records.max_providers_per_key of them are found or there are no more nodes to ask
+    pub fn get_providers(&self, key: String) -> Vec<Contact> {
+        let max_providers = config::get().records.max_providers_per_key;
         let key_id = SHA::hash_string(&key);
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut providers: Vec<Contact> = self.storage.get_providers(&key, now).unwrap_or_default();
         let mut closest_nodes: Vec<Contact> = self.routing_table.find_k_nearest_nodes(key_id);
         let mut queried: HashSet<String> = HashSet::new();
         let mut all_seen: HashSet<String> = HashSet::new();
@@ -344,10 +1222,10 @@ impl Node<SqlLiteStorage> {
             all_seen.insert(key);
         }
 
-        let max_iterations = 3;
+        let max_iterations = config::get().protocol.lookup_iterations;
         let mut iteration = 0;
 
-        while iteration < max_iterations {
+        while iteration < max_iterations && providers.len() < max_providers {
             iteration += 1;
 
             // Select α closest unqueried nodes
@@ -357,7 +1235,7 @@ impl Node<SqlLiteStorage> {
                     let key = format!("{}:{}", node.ip_address, node.port);
                     !queried.contains(&key)
                 })
-                .take(ALPHA)
+                .take(config::get().protocol.alpha)
                 .cloned()
                 .collect();
 
@@ -365,14 +1243,13 @@ impl Node<SqlLiteStorage> {
                 break;
             }
 
-            // Query nodes in parallel
             for node in &to_query {
                 let node_key = format!("{}:{}", node.ip_address, node.port);
                 queried.insert(node_key);
 
-                if let Err(e) = self.send_find_value(key.clone(), vec![*node]) {
+                if let Err(e) = self.send_get_providers(key.clone(), *node) {
                     logWarn!(
-                        "Failed to send FIND_VALUE to {}:{}: {}",
+                        "Failed to send GET_PROVIDERS to {}:{}: {}",
                         node.ip_address,
                         node.port,
                         e
@@ -380,14 +1257,18 @@ impl Node<SqlLiteStorage> {
                     continue;
                 }
 
-                // Wait for response with timeout
-                let (value, nodes) =
-                    self.wait_for_find_value_response(node, Duration::from_secs(2));
-                if let Some(val) = value {
-                    return Some(val); // Found the value!
+                let Some((new_providers, nodes)) =
+                    self.wait_for_get_providers_response(node, &key, config::response_timeout())
+                else {
+                    continue;
+                };
+
+                for provider in new_providers {
+                    if !providers.iter().any(|p| p.node_id == provider.node_id) {
+                        providers.push(provider);
+                    }
                 }
 
-                // If response contains nodes (value not found), add them to closest_nodes
                 for new_node in nodes {
                     let node_key = format!("{}:{}", new_node.ip_address, new_node.port);
                     if !all_seen.contains(&node_key) && new_node.node_id != self.contact.node_id {
@@ -397,58 +1278,240 @@ impl Node<SqlLiteStorage> {
                 }
 
                 // Sort by distance to target and keep only k closest
-                use crate::config::K;
                 closest_nodes.sort_by_key(|contact| contact.node_id ^ key_id);
-                if closest_nodes.len() > K {
-                    closest_nodes.truncate(K);
+                if closest_nodes.len() > config::get().protocol.k {
+                    closest_nodes.truncate(config::get().protocol.k);
                 }
             }
         }
 
-        None
+        providers.truncate(max_providers);
+        providers
     }
 
-    // Wait for a FindValueResponse from a specific node
-    // Returns (value, nodes) where value is Some if found, None if not found
-    // and nodes is the list of closest nodes if value not found
-    fn wait_for_find_value_response(
+    // Wait for a GetProvidersResponse for the key from a specific node
+    // Returns (providers, nodes), or None on timeout
+    fn wait_for_get_providers_response(
         &self,
         target: &Contact,
+        key: &str,
         timeout: Duration,
-    ) -> (Option<String>, Vec<Contact>) {
+    ) -> Option<(Vec<Contact>, Vec<Contact>)> {
         let start = Instant::now();
         let target_key = format!("{}:{}", target.ip_address, target.port);
 
         // Poll the response map for a matching response
         while start.elapsed() < timeout {
-            if let Some(ref response_map) = self.response_map {
-                if let Ok(mut map) = response_map.lock() {
-                    if let Some(msg) = map.remove(&target_key) {
-                        if let MessageType::FindValueResponse { value, nodes } = msg.message_type {
-                            return (value, nodes);
-                        }
-                    }
-                }
+            if let Some(ref response_map) = self.response_map
+                && let Ok(mut map) = response_map.lock()
+                && let Some(msg) = map.remove(&target_key)
+                && let MessageType::GetProvidersResponse {
+                    key: response_key,
+                    providers,
+                    nodes,
+                    token,
+                } = msg.message_type
+                && response_key == key
+            {
+                self.remember_write_token(target, token);
+                return Some((providers, nodes));
+            }
+            thread::sleep(config::poll_interval());
+        }
+
+        metrics::TIMEOUTS.inc(&["get_providers"]);
+        None
+    }
+
+    // Public method to get a value using iterative lookup, erasure coded values are rebuilt
+    // from their shards
+    pub fn get_value(&self, key: String) -> Option<String> {
+        let record = self.get_record(key)?;
+        self.record_value(record)
+    }
+
+    // the same, along with the trace of the lookup, which is empty if the key was settled
+    // locally. The shards of an erasure coded value are looked up without a trace
+    pub fn get_value_traced(&self, key: String) -> (Option<String>, LookupTrace) {
+        let start = Instant::now();
+        let (record, trace) = match self.local_value(key) {
+            Ok((record, trace)) => (record, trace),
+            Err(lookup) => {
+                let lookup = self.iterative_lookup_values(vec![*lookup]).pop().unwrap();
+                (lookup.best, lookup.trace)
             }
-            thread::sleep(Duration::from_millis(100));
+        };
+        let value = record.and_then(|record| self.record_value(record));
+        (
+            value,
+            LookupTrace {
+                took: start.elapsed(),
+                ..trace
+            },
+        )
+    }
+
+    fn record_value(&self, record: Record) -> Option<String> {
+        match record.kind {
+            RecordKind::ErasureCoded { .. } => self.rebuild_erasure_coded(&record),
+            _ => Some(record.value),
+        }
+    }
+
+    // stores the value as data_shards + parity_shards Reed–Solomon shards, each on the few nodes
+    // closest to its own key, and then the record listing them on the k closest nodes of `key`.
+    // Fails if fewer than data_shards shards were confirmed, the value couldn't be rebuilt then
+    pub fn store_erasure_coded(
+        &self,
+        key: String,
+        value: String,
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Result<StoreReport> {
+        if data_shards == 0
+            || parity_shards == 0
+            || data_shards + parity_shards > ERASURE_MAX_SHARDS
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "need at least one data and one parity shard, and at most {} shards",
+                    ERASURE_MAX_SHARDS
+                ),
+            ));
+        }
+        let shards = erasure::encode(value.as_bytes(), data_shards, parity_shards)
+            .ok_or_else(|| std::io::Error::other("failed to encode the value"))?;
+
+        let mut stored = 0;
+        for (index, shard) in shards.iter().enumerate() {
+            let record = Record::new_shard(&self.identity, &key, index as u8, shard);
+            let report = self.store_record(record, None)?;
+            logInfo!("Shard {}: {}", index, report);
+            if report.replicas() > 0 {
+                stored += 1;
+            }
+        }
+        if stored < data_shards {
+            return Err(std::io::Error::other(format!(
+                "only {} of {} shards were stored, {} are needed to rebuild the value",
+                stored,
+                shards.len(),
+                data_shards
+            )));
         }
 
-        (None, Vec::new())
+        let record = Record::new_erasure_coded(
+            &self.identity,
+            key,
+            &shards,
+            data_shards as u8,
+            value.len() as u64,
+        );
+        self.store_record(record, None)
     }
 
-    pub fn store(&self, key: String, value: String) -> Result<()> {
-        let key_id = SHA::hash_string(&key);
-        // Use iterative lookup to find the actual k-nearest nodes
-        let target_nodes = self.iterative_lookup_nodes(key_id);
-        logInfo!("Found {} nodes via iterative lookup", target_nodes.len());
-        self.send_store(key, value, target_nodes)
+    // fetches the shards side by side and rebuilds the value from the ones that are signed by
+    // the record's publisher and hash to what the record lists
+    fn rebuild_erasure_coded(&self, record: &Record) -> Option<String> {
+        let RecordKind::ErasureCoded {
+            data_shards,
+            parity_shards,
+            size,
+        } = record.kind
+        else {
+            return None;
+        };
+        let hashes = record.shard_hashes()?;
+        let keys = (0..hashes.len())
+            .map(|index| Record::shard_key(&record.key, index as u8))
+            .collect();
+        let shards: Vec<Option<Vec<u8>>> = self
+            .get_records(keys)
+            .into_iter()
+            .zip(&hashes)
+            .map(|(shard, hash)| {
+                shard
+                    .filter(|shard| shard.publisher == record.publisher)
+                    .and_then(|shard| shard.content())
+                    .filter(|content| SHA::hash(content) == *hash)
+            })
+            .collect();
+        let found = shards.iter().flatten().count();
+        if found < data_shards as usize {
+            logWarn!(
+                "Only found {} of the {} shards needed to rebuild {}",
+                found,
+                data_shards,
+                record.key
+            );
+            return None;
+        }
+        let value = erasure::decode(
+            shards,
+            data_shards as usize,
+            parity_shards as usize,
+            size as usize,
+        )?;
+        String::from_utf8(value).ok()
     }
 
-    // Public method to get a value using iterative lookup
-    pub fn get_value(&self, key: String) -> Option<String> {
-        // First check local storage
-        if let Ok(Some(value)) = self.storage.get(&key) {
-            return Some(value);
+    pub fn get_record(&self, key: String) -> Option<Record> {
+        self.get_records(vec![key]).pop().flatten()
+    }
+
+    // looks up several keys at once, the ones we can't answer locally are looked up on the
+    // network side by side. Returns one result per key, in order
+    pub fn get_records(&self, keys: Vec<String>) -> Vec<Option<Record>> {
+        let mut results: Vec<Option<Record>> = vec![None; keys.len()];
+        let mut lookups: Vec<(usize, ValueLookup)> = Vec::new();
+        for (index, key) in keys.into_iter().enumerate() {
+            match self.local_value(key) {
+                Ok((record, _)) => results[index] = record,
+                Err(lookup) => lookups.push((index, *lookup)),
+            }
+        }
+
+        let (indexes, lookups): (Vec<usize>, Vec<ValueLookup>) = lookups.into_iter().unzip();
+        for (index, lookup) in indexes
+            .into_iter()
+            .zip(self.iterative_lookup_values(lookups))
+        {
+            results[index] = lookup.best;
+        }
+        results
+    }
+
+    // settles the key from local storage if it can, with the trace saying how, otherwise
+    // returns the lookup to run on the network
+    fn local_value(
+        &self,
+        key: String,
+    ) -> std::result::Result<(Option<Record>, LookupTrace), Box<ValueLookup>> {
+        let trace = |key: &String, stop| LookupTrace {
+            stop,
+            ..LookupTrace::new(SHA::hash_string(key), Some(key.clone()))
+        };
+        // A live tombstone hides the record it deletes. One without a record of its publisher's
+        // to delete settles nothing, it goes with the lookup instead, see handle_answer
+        let local = self.storage.get_record(&key).ok().flatten();
+        let tombstone = self
+            .storage
+            .get_tombstone(&key)
+            .ok()
+            .flatten()
+            .filter(|tombstone| !tombstone.is_expired());
+        if let (Some(record), Some(tombstone)) = (&local, &tombstone)
+            && tombstone.covers(record)
+        {
+            return Ok((None, trace(&key, StopReason::DeletedLocally)));
+        }
+
+        // First check local storage, a local mutable record might have been superseded though
+        if let Some(record) = &local
+            && record.seq().is_none()
+        {
+            return Ok((local, trace(&key, StopReason::FoundLocally)));
         }
 
         // If not found locally, use iterative lookup
@@ -456,6 +1519,179 @@ impl Node<SqlLiteStorage> {
             "Value not found locally, performing iterative lookup for key: {}",
             key
         );
-        self.iterative_lookup_value(key)
+        let mut lookup = ValueLookup::new(key, local);
+        lookup.tombstones.extend(tombstone);
+        Err(Box::new(lookup))
+    }
+}
+
+// what a node answered to a FIND_VALUE: (record, nodes, tombstone)
+type FindValueAnswer = (Option<Record>, Vec<Contact>, Option<Tombstone>);
+
+// the state of one FIND_VALUE lookup, see Node::iterative_lookup_values
+#[derive(Debug)]
+struct ValueLookup {
+    key: String,
+    key_id: SHA,
+    closest: Vec<Contact>,
+    queried: HashSet<String>,
+    all_seen: HashSet<String>,
+    // the α closest unqueried nodes picked at the start of the iteration, not asked yet
+    to_query: VecDeque<Contact>,
+    iteration: usize,
+    best: Option<Record>,
+    // whether best came from a node's answer rather than from our own storage
+    best_from_network: bool,
+    // the valid tombstones we were given or hold, see handle_answer
+    tombstones: Vec<Tombstone>,
+    done: bool,
+    // how long it took to finish, the lookups of a batch finish at different times
+    took: Option<Duration>,
+    trace: LookupTrace,
+}
+
+impl ValueLookup {
+    fn new(key: String, best: Option<Record>) -> Self {
+        let key_id = SHA::hash_string(&key);
+        Self {
+            trace: LookupTrace::new(key_id, Some(key.clone())),
+            key_id,
+            key,
+            closest: Vec::new(),
+            queried: HashSet::new(),
+            all_seen: HashSet::new(),
+            to_query: VecDeque::new(),
+            iteration: 0,
+            best,
+            best_from_network: false,
+            tombstones: Vec::new(),
+            done: false,
+            took: None,
+        }
+    }
+
+    // the next node to ask, None once the lookup is over
+    fn next_target(&mut self) -> Option<Contact> {
+        while !self.done {
+            if let Some(node) = self.to_query.pop_front() {
+                self.queried
+                    .insert(format!("{}:{}", node.ip_address, node.port));
+                return Some(node);
+            }
+            if self.iteration >= config::get().protocol.lookup_iterations {
+                self.trace.stop = StopReason::IterationLimit;
+                self.done = true;
+                break;
+            }
+            self.iteration += 1;
+
+            // Select α closest unqueried nodes
+            self.to_query = self
+                .closest
+                .iter()
+                .filter(|node| {
+                    let key = format!("{}:{}", node.ip_address, node.port);
+                    !self.queried.contains(&key)
+                })
+                .take(config::get().protocol.alpha)
+                .cloned()
+                .collect();
+            if self.to_query.is_empty() {
+                self.trace.stop = if self.closest.is_empty() {
+                    StopReason::NoContacts
+                } else {
+                    StopReason::Exhausted
+                };
+                self.done = true;
+            }
+        }
+        None
+    }
+
+    // what the node answered, for the trace
+    fn handle_answer(&mut self, node: &Contact, own_id: SHA, answer: FindValueAnswer) -> Reply {
+        let (record, nodes, tombstone) = answer;
+        // anyone can sign a tombstone for any key, so it only counts against a record of its
+        // publisher's. Until such a record turns up it is kept and the lookup goes on
+        let mut reply = None;
+        if let Some(tombstone) = tombstone
+            && tombstone.key == self.key
+            && tombstone.verify()
+            && !tombstone.is_expired()
+        {
+            self.tombstones.push(tombstone);
+            reply = Some(Reply::Tombstone);
+        }
+        if let Some(record) = record {
+            if record.key != self.key || !record.verify() {
+                logWarn!(
+                    "Discarding invalid record for key {} from {}:{}",
+                    self.key,
+                    node.ip_address,
+                    node.port
+                );
+                reply = Some(Reply::InvalidRecord);
+            } else if self.is_deleted(&record) {
+                // a later version of a mutable record may have been published since
+                if record.seq().is_none() {
+                    return self.deleted();
+                }
+            } else if record.seq().is_none() {
+                self.best = Some(record); // Found the value!
+                self.best_from_network = true;
+                self.trace.stop = StopReason::Found;
+                self.done = true;
+                return Reply::Record { seq: None };
+            } else {
+                reply = Some(Reply::Record { seq: record.seq() });
+                if self
+                    .best
+                    .as_ref()
+                    .is_none_or(|best| record.seq() > best.seq())
+                {
+                    self.best = Some(record);
+                    self.best_from_network = true;
+                }
+            }
+        }
+        // the tombstone may have come after the version we hold
+        if self.best.as_ref().is_some_and(|best| self.is_deleted(best)) {
+            self.best = None;
+            self.best_from_network = false;
+        }
+
+        // If response contains nodes (value not found), add them to closest_nodes
+        let count = nodes.len();
+        let mut new = 0;
+        for new_node in nodes {
+            let node_key = format!("{}:{}", new_node.ip_address, new_node.port);
+            if !self.all_seen.contains(&node_key) && new_node.node_id != own_id {
+                self.all_seen.insert(node_key);
+                self.closest.push(new_node);
+                new += 1;
+            }
+        }
+
+        // Sort by distance to target and keep only k closest
+        self.closest
+            .sort_by_key(|contact| contact.node_id ^ self.key_id);
+        self.closest.truncate(config::get().protocol.k);
+        reply.unwrap_or(Reply::Nodes { count, new })
+    }
+
+    // whether a tombstone we were given deletes the record
+    fn is_deleted(&self, record: &Record) -> bool {
+        self.tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(record))
+    }
+
+    fn deleted(&mut self) -> Reply {
+        logInfo!("Key {} was deleted by its publisher", self.key);
+        self.best = None;
+        self.best_from_network = false;
+        self.trace.stop = StopReason::Deleted;
+        self.done = true;
+        Reply::Tombstone
     }
 }
@@ -0,0 +1,394 @@
+use crate::config;
+use crate::identity::{Identity, PublicKey, Signature};
+use crate::sha::SHA;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// keys of mutable records live in their own namespace so that nobody can squat the key of
+// someone else's mutable record with a plain one
+pub const MUTABLE_KEY_PREFIX: &str = "mutable:";
+// keys of immutable records are the SHA of their content
+pub const IMMUTABLE_KEY_PREFIX: &str = "immutable:";
+// keys of the shards of an erasure coded value are derived from the value's key and the index
+pub const SHARD_KEY_PREFIX: &str = "shard:";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RecordKind {
+    // a plain pair, the last write of its publisher wins
+    Signed,
+    // BEP 44 style record: keyed by hash(public key || salt),
+    // a storage node only replaces it with a higher sequence number
+    Mutable {
+        salt: String,
+        seq: u64,
+    },
+    // content addressed record: the value is base64 of bytes whose SHA is the key
+    Immutable,
+    // a value stored as Reed–Solomon shards, see erasure.rs: the record's value lists the hex
+    // SHA of every shard separated by commas, size is the length of the value in bytes
+    ErasureCoded {
+        data_shards: u8,
+        parity_shards: u8,
+        size: u64,
+    },
+    // shard `index` of the erasure coded value under `key`, the value is base64 of its bytes
+    Shard {
+        key: String,
+        index: u8,
+    },
+}
+
+// a key-value pair signed by the node that published it, the publisher owns the key:
+// only it can overwrite or delete the pair
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub value: String,
+    pub publisher: PublicKey,
+    pub published_at: i64, // unix millis
+    pub kind: RecordKind,
+    pub signature: Signature,
+}
+
+impl Record {
+    pub fn new(identity: &Identity, key: String, value: String) -> Self {
+        Self::signed(identity, key, value, RecordKind::Signed)
+    }
+
+    pub fn new_mutable(identity: &Identity, salt: String, seq: u64, value: String) -> Self {
+        let key = Self::mutable_key(&identity.public_key(), &salt);
+        Self::signed(identity, key, value, RecordKind::Mutable { salt, seq })
+    }
+
+    pub fn new_immutable(identity: &Identity, content: &[u8]) -> Self {
+        let key = Self::immutable_key(&SHA::hash(content));
+        Self::signed(identity, key, BASE64.encode(content), RecordKind::Immutable)
+    }
+
+    pub fn new_erasure_coded(
+        identity: &Identity,
+        key: String,
+        shards: &[Vec<u8>],
+        data_shards: u8,
+        size: u64,
+    ) -> Self {
+        let hashes: Vec<String> = shards
+            .iter()
+            .map(|shard| SHA::hash(shard).to_hex())
+            .collect();
+        let kind = RecordKind::ErasureCoded {
+            data_shards,
+            parity_shards: shards.len() as u8 - data_shards,
+            size,
+        };
+        Self::signed(identity, key, hashes.join(","), kind)
+    }
+
+    pub fn new_shard(identity: &Identity, key: &str, index: u8, shard: &[u8]) -> Self {
+        let kind = RecordKind::Shard {
+            key: key.to_string(),
+            index,
+        };
+        Self::signed(
+            identity,
+            Self::shard_key(key, index),
+            BASE64.encode(shard),
+            kind,
+        )
+    }
+
+    fn signed(identity: &Identity, key: String, value: String, kind: RecordKind) -> Self {
+        let published_at = Utc::now().timestamp_millis();
+        let signature = identity.sign(&Self::signable(&key, &value, published_at, &kind));
+        Self {
+            key,
+            value,
+            publisher: identity.public_key(),
+            published_at,
+            kind,
+            signature,
+        }
+    }
+
+    pub fn mutable_key(public_key: &PublicKey, salt: &str) -> String {
+        let mut bytes = public_key.0.to_vec();
+        bytes.extend_from_slice(salt.as_bytes());
+        format!("{}{}", MUTABLE_KEY_PREFIX, SHA::hash(&bytes).to_hex())
+    }
+
+    pub fn immutable_key(content_hash: &SHA) -> String {
+        format!("{}{}", IMMUTABLE_KEY_PREFIX, content_hash.to_hex())
+    }
+
+    pub fn shard_key(key: &str, index: u8) -> String {
+        let mut bytes = key.as_bytes().to_vec();
+        bytes.push(index);
+        format!("{}{}", SHARD_KEY_PREFIX, SHA::hash(&bytes).to_hex())
+    }
+
+    // the bytes of an immutable record or a shard, None for the other kinds or if the value
+    // isn't base64
+    pub fn content(&self) -> Option<Vec<u8>> {
+        match self.kind {
+            RecordKind::Immutable | RecordKind::Shard { .. } => BASE64.decode(&self.value).ok(),
+            _ => None,
+        }
+    }
+
+    // the hashes of the shards of an erasure coded record, None for the other kinds or if the
+    // value doesn't list one per shard
+    pub fn shard_hashes(&self) -> Option<Vec<SHA>> {
+        let RecordKind::ErasureCoded {
+            data_shards,
+            parity_shards,
+            ..
+        } = self.kind
+        else {
+            return None;
+        };
+        let hashes: Vec<SHA> = self
+            .value
+            .split(',')
+            .map(SHA::from_hex)
+            .collect::<Option<_>>()?;
+        (hashes.len() == data_shards as usize + parity_shards as usize).then_some(hashes)
+    }
+
+    // how many of the closest nodes to its key should hold the record, shards are spread
+    // over the keyspace instead of being copied to all k
+    pub fn replicas(&self) -> usize {
+        match self.kind {
+            RecordKind::Shard { .. } => config::get().records.erasure_shard_replicas,
+            _ => config::get().protocol.k,
+        }
+    }
+
+    // the sequence number of a mutable record, None for the other kinds
+    pub fn seq(&self) -> Option<u64> {
+        match self.kind {
+            RecordKind::Mutable { seq, .. } => Some(seq),
+            _ => None,
+        }
+    }
+
+    fn signable(key: &str, value: &str, published_at: i64, kind: &RecordKind) -> Vec<u8> {
+        encode(&("store", key, value, published_at, kind))
+    }
+
+    // checks the signature, and that the key is the one the record kind dictates
+    pub fn verify(&self) -> bool {
+        let plain_key = !self.key.starts_with(MUTABLE_KEY_PREFIX)
+            && !self.key.starts_with(IMMUTABLE_KEY_PREFIX)
+            && !self.key.starts_with(SHARD_KEY_PREFIX);
+        let key_matches = match &self.kind {
+            RecordKind::Signed => plain_key,
+            RecordKind::ErasureCoded { .. } => plain_key && self.shard_hashes().is_some(),
+            RecordKind::Shard { key, index } => self.key == Self::shard_key(key, *index),
+            RecordKind::Mutable { salt, .. } => {
+                self.key == Self::mutable_key(&self.publisher, salt)
+            }
+            RecordKind::Immutable => self
+                .content()
+                .is_some_and(|content| self.key == Self::immutable_key(&SHA::hash(&content))),
+        };
+        key_matches
+            && self.publisher.verify(
+                &Self::signable(&self.key, &self.value, self.published_at, &self.kind),
+                &self.signature,
+            )
+    }
+}
+
+// a signed marker saying the publisher deleted its key, storage nodes keep it instead of the
+// value until it expires so that stale replicas can't bring the value back
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Tombstone {
+    pub key: String,
+    pub publisher: PublicKey,
+    pub deleted_at: i64, // unix millis
+    pub expires_at: i64, // unix millis
+    pub signature: Signature,
+}
+
+impl Tombstone {
+    pub fn new(identity: &Identity, key: String, ttl_secs: i64) -> Self {
+        let deleted_at = Utc::now().timestamp_millis();
+        let expires_at = deleted_at + ttl_secs * 1000;
+        let signature = identity.sign(&Self::signable(&key, deleted_at, expires_at));
+        Self {
+            key,
+            publisher: identity.public_key(),
+            deleted_at,
+            expires_at,
+            signature,
+        }
+    }
+
+    fn signable(key: &str, deleted_at: i64, expires_at: i64) -> Vec<u8> {
+        encode(&("delete", key, deleted_at, expires_at))
+    }
+
+    pub fn verify(&self) -> bool {
+        self.publisher.verify(
+            &Self::signable(&self.key, self.deleted_at, self.expires_at),
+            &self.signature,
+        )
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now().timestamp_millis()
+    }
+
+    // a tombstone only deletes what its publisher stored before deleting it
+    pub fn covers(&self, record: &Record) -> bool {
+        self.publisher == record.publisher && self.deleted_at >= record.published_at
+    }
+}
+
+fn encode<T: Serialize>(fields: &T) -> Vec<u8> {
+    let config = bincode::config::standard();
+    bincode::serde::encode_to_vec(fields, config).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a plain record signed as if it was published at the given time
+    fn published_at(identity: &Identity, key: &str, published_at: i64) -> Record {
+        let kind = RecordKind::Signed;
+        let signature = identity.sign(&Record::signable(key, "value", published_at, &kind));
+        Record {
+            key: key.to_string(),
+            value: "value".to_string(),
+            publisher: identity.public_key(),
+            published_at,
+            kind,
+            signature,
+        }
+    }
+
+    // re-signs a record after a field was changed, so that only the key check can fail
+    fn resigned(identity: &Identity, record: Record) -> Record {
+        let signature = identity.sign(&Record::signable(
+            &record.key,
+            &record.value,
+            record.published_at,
+            &record.kind,
+        ));
+        Record {
+            signature,
+            ..record
+        }
+    }
+
+    #[test]
+    fn records_of_every_kind_verify() {
+        let identity = Identity::generate();
+        assert!(Record::new(&identity, "key".to_string(), "a".to_string()).verify());
+        assert!(Record::new_mutable(&identity, "salt".to_string(), 1, "a".to_string()).verify());
+        assert!(Record::new_immutable(&identity, b"content").verify());
+        assert!(Record::new_shard(&identity, "key", 0, b"shard").verify());
+    }
+
+    #[test]
+    fn tampered_record_fails_verification() {
+        let identity = Identity::generate();
+        let mut record = Record::new(&identity, "key".to_string(), "a".to_string());
+        record.published_at += 1;
+        assert!(!record.verify());
+
+        let mut record = Record::new(&identity, "key".to_string(), "a".to_string());
+        record.publisher = Identity::generate().public_key();
+        assert!(!record.verify());
+    }
+
+    #[test]
+    fn key_must_match_the_kind() {
+        let identity = Identity::generate();
+        // a plain record can't take a key from the reserved namespaces
+        let mutable = Record::new_mutable(&identity, "salt".to_string(), 1, "a".to_string());
+        let plain = Record::new(&identity, mutable.key.clone(), "a".to_string());
+        assert!(!plain.verify());
+
+        // nor can a mutable record claim another salt's or another publisher's key
+        let other_salt = Record::new_mutable(&identity, "other".to_string(), 1, "a".to_string());
+        let record = Record {
+            key: other_salt.key,
+            ..mutable.clone()
+        };
+        assert!(!resigned(&identity, record).verify());
+        let other = Identity::generate();
+        let record = Record {
+            publisher: other.public_key(),
+            ..mutable
+        };
+        assert!(!resigned(&other, record).verify());
+
+        let record = Record {
+            kind: RecordKind::Signed,
+            ..Record::new_shard(&identity, "key", 0, b"shard")
+        };
+        assert!(!resigned(&identity, record).verify());
+    }
+
+    #[test]
+    fn immutable_key_must_be_the_content_hash() {
+        let identity = Identity::generate();
+        let record = Record::new_immutable(&identity, b"content");
+        assert_eq!(record.key, Record::immutable_key(&SHA::hash(b"content")));
+        let record = Record {
+            value: BASE64.encode(b"other content"),
+            ..record
+        };
+        assert!(!resigned(&identity, record).verify());
+        let record = Record {
+            value: "not base64!".to_string(),
+            ..Record::new_immutable(&identity, b"content")
+        };
+        assert!(!resigned(&identity, record).verify());
+    }
+
+    #[test]
+    fn seq_is_only_set_for_mutable_records() {
+        let identity = Identity::generate();
+        let record = Record::new_mutable(&identity, "salt".to_string(), 7, "a".to_string());
+        assert_eq!(record.seq(), Some(7));
+        assert_eq!(
+            Record::new(&identity, "key".to_string(), "a".to_string()).seq(),
+            None
+        );
+    }
+
+    #[test]
+    fn tombstone_covers_what_its_publisher_stored_before() {
+        let identity = Identity::generate();
+        let tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        let deleted_at = tombstone.deleted_at;
+        assert!(tombstone.verify());
+        assert!(tombstone.covers(&published_at(&identity, "key", deleted_at - 1)));
+        assert!(tombstone.covers(&published_at(&identity, "key", deleted_at)));
+        assert!(!tombstone.covers(&published_at(&identity, "key", deleted_at + 1)));
+    }
+
+    #[test]
+    fn tombstone_does_not_cover_another_publishers_record() {
+        let identity = Identity::generate();
+        let tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        let other = published_at(&Identity::generate(), "key", tombstone.deleted_at - 1);
+        assert!(!tombstone.covers(&other));
+    }
+
+    #[test]
+    fn tampered_tombstone_fails_verification() {
+        let identity = Identity::generate();
+        let mut tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        tombstone.expires_at += 1;
+        assert!(!tombstone.verify());
+
+        let mut tombstone = Tombstone::new(&identity, "key".to_string(), 60);
+        tombstone.publisher = Identity::generate().public_key();
+        assert!(!tombstone.verify());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     bucket::KBucket,
-    config::{ID_BITS, K},
+    config::{self, ID_BITS},
     contact::Contact,
     logInfo,
     sha::SHA,
@@ -15,7 +15,7 @@ pub struct RoutingTable {
 impl RoutingTable {
     pub fn new(local_node_id: SHA) -> Self {
         Self {
-            buckets: std::array::from_fn(|i| KBucket::new(i)),
+            buckets: std::array::from_fn(KBucket::new),
             local_node_id,
         }
     }
@@ -35,17 +35,30 @@ impl RoutingTable {
         0
     }
 
-    pub fn insert_node(&mut self, new_node: &Contact) {
+    // returns true when the node wasn't in the table yet
+    pub fn insert_node(&mut self, new_node: &Contact) -> bool {
         logInfo!(
             "inserting node with address {}:{} to our routing table",
             new_node.ip_address,
             new_node.port
         );
+        // the bootstrap node goes in under a placeholder id made from its address until it
+        // answers with its own, then that entry is dropped. Only the placeholder is: packets
+        // aren't authenticated, so anyone can claim a live contact's address under another id
+        let placeholder = SHA::hash_string(&format!("{}:{}", new_node.ip_address, new_node.port));
+        if new_node.node_id != placeholder {
+            for bucket in self.buckets.iter_mut() {
+                bucket.nodes.retain(|n| n.node_id != placeholder);
+            }
+        }
         let bucket = &mut self.buckets[self.find_bucket(new_node.node_id)];
-        bucket.add(&new_node);
+        let is_new = bucket.find_element(new_node.node_id).is_none();
+        bucket.add(new_node);
+        is_new
     }
 
     pub fn find_k_nearest_nodes(&self, target_id: SHA) -> Vec<Contact> {
+        let k = config::get().protocol.k;
         let mut nodes = Vec::new();
         let i = self.find_bucket(target_id);
         nodes.extend(self.buckets[i].get_nodes());
@@ -53,7 +66,7 @@ impl RoutingTable {
         let mut left: isize = i as isize - 1;
         let mut right: usize = i + 1;
 
-        while nodes.len() < K && (left >= 0 || right < ID_BITS) {
+        while nodes.len() < k && (left >= 0 || right < ID_BITS) {
             if left >= 0 {
                 nodes.extend(self.buckets[left as usize].get_nodes());
                 left -= 1;
@@ -67,8 +80,8 @@ impl RoutingTable {
         // sort the contacts by distance to target_id
         nodes.sort_by_key(|contact| contact.node_id ^ target_id);
 
-        if nodes.len() > K {
-            nodes.truncate(K);
+        if nodes.len() > k {
+            nodes.truncate(k);
         }
 
         nodes
@@ -0,0 +1,196 @@
+// A running node: its listener, its maintenance and whatever else serves it (the stdin loop,
+// the control socket, the HTTP gateway), and the shutdown that stops them in order. Shutting
+// down first stops everything that takes new work and waits for what it is doing, then
+// optionally hands the stored records over to the neighbours while the network still runs,
+// writes the node's state, and finally stops the listener, which waits for the messages it
+// is handling. The CLI's close, the control socket's shutdown, SIGINT/SIGTERM and
+// NodeHandle::shutdown all end up here.
+use crate::{config, logError, logInfo, node::Node, storage::Storage};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// how a running node is told to stop, shared by everything that can stop it
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    // hand the stored records over to the neighbours before leaving
+    hand_off: AtomicBool,
+}
+
+impl Shutdown {
+    pub fn new(hand_off: bool) -> Self {
+        Self {
+            requested: Arc::new(AtomicBool::new(false)),
+            hand_off: AtomicBool::new(hand_off),
+        }
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    // a request can ask for the handoff even if the node wasn't started with it, not the
+    // other way around
+    pub fn request_with_hand_off(&self) {
+        self.hand_off.store(true, Ordering::SeqCst);
+        self.request();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn hands_off(&self) -> bool {
+        self.hand_off.load(Ordering::SeqCst)
+    }
+
+    // the flag itself, for the loops that only check it
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.requested
+    }
+
+    pub fn wait(&self) {
+        while !self.is_requested() {
+            thread::sleep(config::poll_interval());
+        }
+    }
+}
+
+pub struct NodeHandle<T: Storage> {
+    node: Arc<Mutex<Node<T>>>,
+    shutdown: Arc<Shutdown>,
+    // stops the listener, the last thing to go
+    network_stop: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<T: Storage + Send + 'static> NodeHandle<T> {
+    // starts the node's listener and maintenance
+    pub fn start(node: Node<T>, hand_off: bool) -> Self {
+        let node = Arc::new(Mutex::new(node));
+        let network_stop = Arc::new(AtomicBool::new(false));
+        let listener = Node::spawn_listener(&node, Arc::clone(&network_stop));
+        let mut handle = Self {
+            node,
+            shutdown: Arc::new(Shutdown::new(hand_off)),
+            network_stop,
+            listener: Some(listener),
+            threads: Vec::new(),
+        };
+        handle.spawn(|node, shutdown| maintenance(&node, shutdown.flag()));
+        handle
+    }
+
+    pub fn node(&self) -> &Arc<Mutex<Node<T>>> {
+        &self.node
+    }
+
+    pub fn shutdown_signal(&self) -> &Arc<Shutdown> {
+        &self.shutdown
+    }
+
+    // runs task on a thread the shutdown waits for, so the task should return soon after
+    // the shutdown is requested
+    pub fn spawn<F>(&mut self, task: F)
+    where
+        F: FnOnce(Arc<Mutex<Node<T>>>, Arc<Shutdown>) + Send + 'static,
+    {
+        let node = Arc::clone(&self.node);
+        let shutdown = Arc::clone(&self.shutdown);
+        self.threads
+            .push(thread::spawn(move || task(node, shutdown)));
+    }
+
+    // the first SIGINT or SIGTERM requests the shutdown, a second one ends the process right
+    // away in case the shutdown hangs
+    pub fn shutdown_on_signals(&self) -> Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(
+                signal,
+                1,
+                Arc::clone(self.shutdown.flag()),
+            )?;
+            signal_hook::flag::register(signal, Arc::clone(self.shutdown.flag()))?;
+        }
+        Ok(())
+    }
+
+    pub fn run_until_shutdown(self) -> Result<()> {
+        self.shutdown.wait();
+        self.shutdown()
+    }
+
+    // fails if the node's state couldn't be written, everything is stopped either way
+    pub fn shutdown(mut self) -> Result<()> {
+        self.shutdown.request();
+        logInfo!("Shutting down, waiting for the work in progress");
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if self.shutdown.hands_off() {
+            match Node::hand_off_on_departure(&self.node) {
+                Ok(sent) => logInfo!("Handed {} records over to the neighbours", sent),
+                Err(e) => logError!("Failed to hand the records over: {}", e),
+            }
+        }
+        let saved = self.node.lock().unwrap().save_state();
+        self.network_stop.store(true, Ordering::SeqCst);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+        logInfo!("Shut down");
+        saved
+    }
+}
+
+// hands records over to nodes that joined close to them, and periodically snapshots the
+// routing table, reconciles records with the neighbours, checks the stored records,
+// republishes the tombstones we hold until they expire, and forgets the providers that
+// weren't announced again in time
+fn maintenance<T: Storage>(node: &Arc<Mutex<Node<T>>>, shutdown: &AtomicBool) {
+    let config = &config::get().maintenance;
+    let mut last_run = Instant::now();
+    let mut last_snapshot = Instant::now();
+    let mut last_verify = Instant::now();
+    let mut last_sync = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+        Node::hand_off_pending(node, shutdown);
+        if last_sync.elapsed() >= Duration::from_secs(config.sync_interval_secs) {
+            last_sync = Instant::now();
+            if let Err(e) = node.lock().unwrap().sync_with_neighbours() {
+                logError!("Failed to start anti-entropy: {}", e);
+            }
+        }
+        if last_verify.elapsed() >= Duration::from_secs(config.verify_interval_secs) {
+            last_verify = Instant::now();
+            match node.lock().unwrap().verify_and_repair() {
+                Ok((report, repaired)) => logInfo!("{}, {} fetched again", report, repaired),
+                Err(e) => logError!("Failed to verify the storage: {}", e),
+            }
+        }
+        if last_snapshot.elapsed() >= Duration::from_secs(config.routing_table_snapshot_secs) {
+            last_snapshot = Instant::now();
+            if let Err(e) = node.lock().unwrap().save_routing_table() {
+                logError!("Failed to save the routing table: {}", e);
+            }
+        }
+        if last_run.elapsed() < Duration::from_secs(config.republish_interval_secs) {
+            continue;
+        }
+        last_run = Instant::now();
+        let node = node.lock().unwrap();
+        if let Err(e) = node.republish_tombstones() {
+            logError!("Failed to republish tombstones: {}", e);
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        if let Err(e) = node.storage.remove_expired_providers(now) {
+            logError!("Failed to drop expired providers: {}", e.message);
+        }
+    }
+}
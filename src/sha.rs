@@ -24,6 +24,21 @@ impl SHA {
         SHA(id)
     }
 
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 40 || !hex.is_ascii() {
+            return None;
+        }
+        let mut id = [0u8; 20];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(SHA(id))
+    }
+
     pub fn hash_string(key: &String) -> Self {
         let mut hasher = Sha1::new();
         hasher.update(key);
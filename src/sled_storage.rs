@@ -0,0 +1,255 @@
+use crate::{
+    config,
+    contact::Contact,
+    logDebug,
+    record::{Record, Tombstone},
+    storage::{KeyRange, Page, Storage, StorageError, StorageResult, StorageStats},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::path::Path;
+
+// an embedded log-structured KV store, for nodes that take a lot of writes,
+// every table of the SQLite schema is a sled tree holding bincode values
+#[derive(Debug)]
+pub struct SledStorage {
+    data: sled::Tree,
+    tombstones: sled::Tree,
+    providers: sled::Tree,
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(error: sled::Error) -> Self {
+        StorageError {
+            message: error.to_string(),
+        }
+    }
+}
+
+impl SledStorage {
+    pub fn new(path: impl AsRef<Path>) -> StorageResult<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            data: db.open_tree("data")?,
+            tombstones: db.open_tree("tombstones")?,
+            providers: db.open_tree("providers")?,
+        })
+    }
+
+    fn encode<V: Serialize>(value: &V) -> StorageResult<Vec<u8>> {
+        let config = bincode::config::standard();
+        bincode::serde::encode_to_vec(value, config).map_err(|e| StorageError {
+            message: e.to_string(),
+        })
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> StorageResult<V> {
+        let config = bincode::config::standard();
+        bincode::serde::decode_from_slice(bytes, config)
+            .map(|(value, _)| value)
+            .map_err(|e| StorageError {
+                message: e.to_string(),
+            })
+    }
+
+    fn range(&self, range: &KeyRange, after: Option<&str>) -> sled::Iter {
+        let (lower, upper) = range.key_bounds(after);
+        self.data.range::<String, _>((lower, upper))
+    }
+
+    // the value, and the record when it was stored signed
+    fn get_entry(&self, key: &str) -> StorageResult<Option<(String, Option<Record>)>> {
+        match self.data.get(key)? {
+            Some(bytes) => Ok(Some(Self::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_provider_entries(&self, key: &str) -> StorageResult<Vec<(Contact, i64)>> {
+        match self.providers.get(key)? {
+            Some(bytes) => Self::decode(&bytes),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl Storage for SledStorage {
+    fn print(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn store(&self, key: &str, value: &String) -> StorageResult<()> {
+        let entry: (&String, Option<Record>) = (value, None);
+        self.data.insert(key, Self::encode(&entry)?)?;
+        logDebug!("inserted successfully");
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Option<String>> {
+        Ok(self.get_entry(key)?.map(|(value, _)| value))
+    }
+
+    fn remove(&self, key: &str) -> StorageResult<()> {
+        if self.data.remove(key)?.is_some() {
+            logDebug!("deleted the pair of key : {} ", key);
+        } else {
+            logDebug!("no such key");
+        }
+        Ok(())
+    }
+
+    fn contains(&self, key: &str) -> StorageResult<bool> {
+        Ok(self.data.contains_key(key)?)
+    }
+
+    fn list(
+        &self,
+        range: &KeyRange,
+        after: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<Page<String>> {
+        let mut entries = Vec::new();
+        for item in self.range(range, after).take(limit) {
+            let (key, bytes) = item?;
+            let (value, _): (String, Option<Record>) = Self::decode(&bytes)?;
+            entries.push((String::from_utf8_lossy(&key).into_owned(), value));
+        }
+        Ok(Page::new(entries, limit))
+    }
+
+    fn count(&self, range: &KeyRange) -> StorageResult<usize> {
+        let mut count = 0;
+        for item in self.range(range, None) {
+            item?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn stats(&self) -> StorageResult<StorageStats> {
+        let mut stats = StorageStats::default();
+        for item in self.data.iter() {
+            let (_, bytes) = item?;
+            let (value, record): (String, Option<Record>) = Self::decode(&bytes)?;
+            stats.entries += 1;
+            stats.records += record.is_some() as usize;
+            stats.value_bytes += value.len() as u64;
+        }
+        stats.tombstones = self.tombstones.len();
+        for item in self.providers.iter() {
+            let (_, bytes) = item?;
+            let providers: Vec<(Contact, i64)> = Self::decode(&bytes)?;
+            stats.providers += providers.len();
+        }
+        Ok(stats)
+    }
+
+    fn for_each_entry(
+        &self,
+        visit: &mut dyn FnMut(String, String, Option<Record>) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        for item in self.data.iter() {
+            let (key, bytes) = item?;
+            let (value, record): (String, Option<Record>) = Self::decode(&bytes)?;
+            visit(String::from_utf8_lossy(&key).into_owned(), value, record)?;
+        }
+        Ok(())
+    }
+
+    fn store_record(&self, record: &Record) -> StorageResult<()> {
+        let entry = (&record.value, Some(record));
+        self.data.insert(record.key.as_str(), Self::encode(&entry)?)?;
+        logDebug!("inserted record for key {} successfully", record.key);
+        Ok(())
+    }
+
+    fn get_record(&self, key: &str) -> StorageResult<Option<Record>> {
+        Ok(self.get_entry(key)?.and_then(|(_, record)| record))
+    }
+
+    fn store_tombstone(&self, tombstone: &Tombstone) -> StorageResult<()> {
+        self.tombstones
+            .insert(tombstone.key.as_str(), Self::encode(tombstone)?)?;
+        self.data.remove(tombstone.key.as_str())?;
+        logDebug!("stored tombstone for key {}", tombstone.key);
+        Ok(())
+    }
+
+    fn get_tombstone(&self, key: &str) -> StorageResult<Option<Tombstone>> {
+        match self.tombstones.get(key)? {
+            Some(bytes) => Ok(Some(Self::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_tombstone(&self, key: &str) -> StorageResult<()> {
+        self.tombstones.remove(key)?;
+        Ok(())
+    }
+
+    fn list_tombstones(&self) -> StorageResult<Vec<Tombstone>> {
+        let mut results = Vec::new();
+        for item in self.tombstones.iter() {
+            let (_, bytes) = item?;
+            results.push(Self::decode(&bytes)?);
+        }
+        Ok(results)
+    }
+
+    fn remove_expired_tombstones(&self, now: i64) -> StorageResult<usize> {
+        let mut removed = 0;
+        for tombstone in self.list_tombstones()? {
+            if tombstone.expires_at <= now {
+                self.tombstones.remove(tombstone.key.as_str())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn add_provider(&self, key: &str, provider: &Contact, expires_at: i64) -> StorageResult<()> {
+        let mut providers = self.get_provider_entries(key)?;
+        providers.retain(|(contact, _)| contact.node_id != provider.node_id);
+        providers.push((*provider, expires_at));
+        // keep the ones furthest from expiring
+        providers.sort_by_key(|(_, expires_at)| std::cmp::Reverse(*expires_at));
+        providers.truncate(config::get().records.max_providers_per_key);
+        self.providers.insert(key, Self::encode(&providers)?)?;
+        Ok(())
+    }
+
+    fn get_providers(&self, key: &str, now: i64) -> StorageResult<Vec<Contact>> {
+        Ok(self
+            .get_provider_entries(key)?
+            .into_iter()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(contact, _)| contact)
+            .collect())
+    }
+
+    fn remove_expired_providers(&self, now: i64) -> StorageResult<usize> {
+        let mut removed = 0;
+        for item in self.providers.iter() {
+            let (key, bytes) = item?;
+            let mut providers: Vec<(Contact, i64)> = Self::decode(&bytes)?;
+            let before = providers.len();
+            providers.retain(|(_, expires_at)| *expires_at > now);
+            if providers.len() == before {
+                continue;
+            }
+            removed += before - providers.len();
+            if providers.is_empty() {
+                self.providers.remove(key)?;
+            } else {
+                self.providers.insert(key, Self::encode(&providers)?)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        for tree in [&self.data, &self.tombstones, &self.providers] {
+            tree.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,19 @@
-use rusqlite::{Connection, OptionalExtension, params};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, ToSql, params};
+use serde::Serialize;
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
 
-use crate::{logInfo, logWarn};
+use crate::{
+    config,
+    contact::Contact,
+    identity::{PublicKey, Signature},
+    logDebug, logError, logWarn, migrations,
+    record::{Record, RecordKind, Tombstone},
+    sha::SHA,
+};
 
 pub type StorageResult<T, E = StorageError> = Result<T, E>;
 
@@ -9,6 +22,134 @@ pub struct StorageError {
     pub message: String,
 }
 
+// which keys a listing or count covers, start is inclusive and end exclusive,
+// a prefix narrows the range further
+#[derive(Debug, Clone, Default)]
+pub struct KeyRange {
+    pub prefix: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+impl KeyRange {
+    pub fn prefix(prefix: &str) -> Self {
+        Self {
+            prefix: Some(prefix.to_string()),
+            ..Self::default()
+        }
+    }
+
+    // the range as plain bounds, so backends can answer it from their ordered key index
+    pub fn bounds(&self) -> (Option<String>, Option<String>) {
+        let Some(prefix) = &self.prefix else {
+            return (self.start.clone(), self.end.clone());
+        };
+        let start = match &self.start {
+            Some(start) if start > prefix => start.clone(),
+            _ => prefix.clone(),
+        };
+        let end = match (&self.end, prefix_end(prefix)) {
+            (Some(end), Some(prefix_end)) => Some(end.clone().min(prefix_end)),
+            (end, prefix_end) => end.clone().or(prefix_end),
+        };
+        (Some(start), end)
+    }
+
+    // the bounds of the keys after the cursor, for backends with range iterators
+    pub fn key_bounds(&self, after: Option<&str>) -> (Bound<String>, Bound<String>) {
+        let (start, end) = self.bounds();
+        let lower = match (start, after) {
+            (Some(start), Some(after)) if start.as_str() > after => Bound::Included(start),
+            (_, Some(after)) => Bound::Excluded(after.to_string()),
+            (Some(start), None) => Bound::Included(start),
+            (None, None) => Bound::Unbounded,
+        };
+        let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+        (lower, upper)
+    }
+}
+
+// the smallest key greater than every key starting with prefix, keys are ordered by their
+// UTF-8 bytes which is the order of their code points. None when there isn't one
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // skipping over the surrogates, which aren't chars
+        let next = match last {
+            '\u{D7FF}' => Some('\u{E000}'),
+            _ => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+// one page of a listing, pass next_cursor back to get the one after it
+#[derive(Debug)]
+pub struct Page<V = String> {
+    pub entries: Vec<(String, V)>,
+    pub next_cursor: Option<String>,
+}
+
+impl<V> Page<V> {
+    // expects at most limit entries in key order
+    pub fn new(entries: Vec<(String, V)>, limit: usize) -> Self {
+        let next_cursor = match entries.last() {
+            Some((key, _)) if entries.len() == limit => Some(key.clone()),
+            _ => None,
+        };
+        Self {
+            entries,
+            next_cursor,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StorageStats {
+    pub entries: usize,
+    // entries stored as signed records, the rest are plain pairs
+    pub records: usize,
+    pub value_bytes: u64,
+    pub tombstones: usize,
+    pub providers: usize,
+}
+
+impl fmt::Display for StorageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries ({} signed records, {} bytes of values), {} tombstones, {} provider records",
+            self.entries, self.records, self.value_bytes, self.tombstones, self.providers
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    // stored entries and tombstones that were looked at
+    pub checked: usize,
+    // what the database's own consistency check found, empty when it is healthy
+    pub integrity_errors: Vec<String>,
+    // keys whose records were damaged and moved out of the way, to be fetched again
+    pub quarantined: Vec<String>,
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked {} entries, {} quarantined, {} integrity errors",
+            self.checked,
+            self.quarantined.len(),
+            self.integrity_errors.len()
+        )
+    }
+}
+
 pub trait Storage<V = String> {
     fn print(&self) -> StorageResult<()>;
 
@@ -19,7 +160,74 @@ pub trait Storage<V = String> {
     fn get(&self, key: &str) -> StorageResult<Option<V>>;
     fn remove(&self, key: &str) -> StorageResult<()>;
     fn contains(&self, key: &str) -> StorageResult<bool>;
-    fn list_all(&self) -> StorageResult<Vec<(String, String)>>;
+    // the first limit pairs in range with keys after the cursor, in key order
+    fn list(&self, range: &KeyRange, after: Option<&str>, limit: usize) -> StorageResult<Page<V>>;
+    fn count(&self, range: &KeyRange) -> StorageResult<usize>;
+    fn stats(&self) -> StorageResult<StorageStats>;
+    // calls visit with every stored pair, and its record if it was stored as one,
+    // without loading them all into memory first
+    fn for_each_entry(
+        &self,
+        visit: &mut dyn FnMut(String, String, Option<Record>) -> StorageResult<()>,
+    ) -> StorageResult<()>;
+
+    // signed records, get_record returns None for pairs that were stored without a publisher
+    fn store_record(&self, record: &Record) -> StorageResult<()>;
+    // backends that can should write the whole batch at once
+    fn store_records(&self, records: &[Record]) -> StorageResult<()> {
+        for record in records {
+            self.store_record(record)?;
+        }
+        Ok(())
+    }
+    fn get_record(&self, key: &str) -> StorageResult<Option<Record>>;
+
+    // storing a tombstone also drops the value it deletes
+    fn store_tombstone(&self, tombstone: &Tombstone) -> StorageResult<()>;
+    fn get_tombstone(&self, key: &str) -> StorageResult<Option<Tombstone>>;
+    fn remove_tombstone(&self, key: &str) -> StorageResult<()>;
+    fn list_tombstones(&self) -> StorageResult<Vec<Tombstone>>;
+    fn remove_expired_tombstones(&self, now: i64) -> StorageResult<usize>;
+
+    // the peers that announced they provide a key, adding a provider past
+    // records.max_providers_per_key evicts the ones closest to expiring
+    fn add_provider(&self, key: &str, provider: &Contact, expires_at: i64) -> StorageResult<()>;
+    fn get_providers(&self, key: &str, now: i64) -> StorageResult<Vec<Contact>>;
+    fn remove_expired_providers(&self, now: i64) -> StorageResult<usize>;
+
+    // checks every record and tombstone against its signature and key (content hash for
+    // immutable records). Damaged ones are quarantined, here that means dropped, and their
+    // keys returned so they can be fetched again
+    fn verify(&self) -> StorageResult<VerifyReport> {
+        let mut report = VerifyReport::default();
+        self.for_each_entry(&mut |key, _, record| {
+            report.checked += 1;
+            if record.is_some_and(|record| !record.verify()) {
+                report.quarantined.push(key);
+            }
+            Ok(())
+        })?;
+        for key in &report.quarantined {
+            logWarn!("Quarantining the damaged record for key {}", key);
+            self.remove(key)?;
+        }
+        for tombstone in self.list_tombstones()? {
+            report.checked += 1;
+            if !tombstone.verify() {
+                logWarn!("Dropping the damaged tombstone for key {}", tombstone.key);
+                self.remove_tombstone(&tombstone.key)?;
+            }
+        }
+        Ok(report)
+    }
+    // called once a quarantined key has been fetched and stored again
+    fn release_quarantined(&self, _key: &str) -> StorageResult<()> {
+        Ok(())
+    }
+    // makes sure everything written so far is on disk, called when the node shuts down
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
 }
 impl From<rusqlite::Error> for StorageError {
     fn from(error: rusqlite::Error) -> Self {
@@ -31,32 +239,169 @@ impl From<rusqlite::Error> for StorageError {
 
 impl From<StorageError> for std::io::Error {
     fn from(error: StorageError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, error.message)
+        std::io::Error::other(error.message)
     }
 }
 
+// how many read-only connections SqlLiteStorage keeps next to its writer
+const SQLITE_READERS: usize = 4;
+
+// long-lived connections in WAL mode with statements prepared once then reused from each
+// connection's cache: one that every write goes through, and a few read-only ones so that
+// lookups from several threads don't queue behind each other or behind a write. WAL lets the
+// readers see the last committed state while the writer works
 #[derive(Debug)]
 pub struct SqlLiteStorage {
-    db_name: String,
+    conn: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
 }
 
 impl SqlLiteStorage {
-    pub fn new(name: &str) -> StorageResult<Self> {
-        Self::init_db(name)?;
+    pub fn new(name: impl AsRef<Path>) -> StorageResult<Self> {
+        let name = name.as_ref();
+        let mut conn = Connection::open(name)?;
+        // journal_mode returns the resulting mode as a row
+        let _mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        // with WAL, NORMAL only risks the last transactions on power loss, never corruption
+        conn.execute_batch("PRAGMA synchronous = NORMAL; PRAGMA busy_timeout = 5000;")?;
+        conn.set_prepared_statement_cache_capacity(32);
+        migrations::migrate(&mut conn)?;
+        // opened once the schema is in place
+        let readers = (0..SQLITE_READERS)
+            .map(|_| {
+                let reader = Connection::open_with_flags(
+                    name,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                        | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+                reader.execute_batch("PRAGMA busy_timeout = 5000;")?;
+                reader.set_prepared_statement_cache_capacity(32);
+                Ok(Mutex::new(reader))
+            })
+            .collect::<StorageResult<_>>()?;
         Ok(Self {
-            db_name: name.to_string(),
+            conn: Mutex::new(conn),
+            readers,
+            next_reader: AtomicUsize::new(0),
         })
     }
 
-    fn init_db(name: &str) -> StorageResult<()> {
-        let conn = Connection::open(name)?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS data (
-            key TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        )",
-            [],
-        )?;
+    // the writer
+    fn connection(&self) -> StorageResult<MutexGuard<'_, Connection>> {
+        self.conn.lock().map_err(|e| StorageError {
+            message: e.to_string(),
+        })
+    }
+
+    // a free reader if there is one, else waits for the next one in turn
+    fn reader(&self) -> StorageResult<MutexGuard<'_, Connection>> {
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.readers.len() {
+            let index = (start + offset) % self.readers.len();
+            if let Ok(reader) = self.readers[index].try_lock() {
+                return Ok(reader);
+            }
+        }
+        self.readers[start % self.readers.len()]
+            .lock()
+            .map_err(|e| StorageError {
+                message: e.to_string(),
+            })
+    }
+
+    // expects key, value, publisher, published_at, signature, kind, with a non NULL publisher
+    fn row_to_record(row: &rusqlite::Row) -> rusqlite::Result<Record> {
+        let kind: Option<String> = row.get(5)?;
+        Ok(Record {
+            key: row.get(0)?,
+            value: row.get(1)?,
+            publisher: PublicKey(row.get(2)?),
+            published_at: row.get(3)?,
+            kind: kind
+                .and_then(|kind| serde_json::from_str(&kind).ok())
+                .unwrap_or(RecordKind::Signed),
+            signature: Signature(row.get(4)?),
+        })
+    }
+
+    // the WHERE clause for a range on the primary key, so SQLite can seek to the first key
+    // instead of scanning the table. The bounds only decide which statement is used, the
+    // values are always bound as parameters
+    fn range_clause(range: &KeyRange, after: Option<&str>) -> (String, Vec<Box<dyn ToSql>>) {
+        let (start, end) = range.bounds();
+        let mut conditions = Vec::new();
+        let mut args: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(start) = start {
+            args.push(Box::new(start));
+            conditions.push(format!("key >= ?{}", args.len()));
+        }
+        if let Some(end) = end {
+            args.push(Box::new(end));
+            conditions.push(format!("key < ?{}", args.len()));
+        }
+        if let Some(after) = after {
+            args.push(Box::new(after.to_string()));
+            conditions.push(format!("key > ?{}", args.len()));
+        }
+        if conditions.is_empty() {
+            (String::new(), args)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), args)
+        }
+    }
+
+    // why a data row (with its size as the 7th column) is damaged, None when it is fine
+    fn check_row(row: &rusqlite::Row) -> rusqlite::Result<Option<String>> {
+        let value: String = row.get(1)?;
+        let size: Option<usize> = row.get(6)?;
+        if size.is_some_and(|size| size != value.len()) {
+            return Ok(Some(format!(
+                "value is {} bytes but was stored with {}",
+                value.len(),
+                size.unwrap_or_default()
+            )));
+        }
+        let publisher: Option<Vec<u8>> = row.get(2)?;
+        if publisher.is_some() && !Self::row_to_record(row)?.verify() {
+            return Ok(Some(
+                "signature or key doesn't match the record".to_string(),
+            ));
+        }
+        Ok(None)
+    }
+
+    fn row_to_tombstone(row: &rusqlite::Row) -> rusqlite::Result<Tombstone> {
+        Ok(Tombstone {
+            key: row.get(0)?,
+            publisher: PublicKey(row.get(1)?),
+            deleted_at: row.get(2)?,
+            expires_at: row.get(3)?,
+            signature: Signature(row.get(4)?),
+        })
+    }
+
+    fn insert_record(conn: &Connection, record: &Record) -> StorageResult<()> {
+        conn.prepare_cached(
+            "INSERT INTO data (key, value, publisher, published_at, signature, kind, stored_at, size)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT
+            DO
+            UPDATE SET value = ?2, publisher = ?3, published_at = ?4, signature = ?5, kind = ?6,
+            stored_at = ?7, size = ?8
+            WHERE key = ?1",
+        )?
+        .execute(params![
+            record.key,
+            record.value,
+            record.publisher.0,
+            record.published_at,
+            record.signature.0,
+            serde_json::to_string(&record.kind).unwrap(),
+            chrono::Utc::now().timestamp_millis(),
+            record.value.len()
+        ])?;
         Ok(())
     }
 }
@@ -67,41 +412,47 @@ impl Storage for SqlLiteStorage {
     }
 
     fn store(&self, key: &str, value: &String) -> StorageResult<()> {
-        let conn = Connection::open(self.db_name.clone())?;
-        let num = conn.execute(
-            "INSERT INTO data (key, value) VALUES (?1, ?2) 
-            ON CONFLICT 
-            DO
-            UPDATE SET value = ?2
-            WHERE key = ?1",
-            params![key, value],
-        )?;
+        let conn = self.connection()?;
+        let num = conn
+            .prepare_cached(
+                "INSERT INTO data (key, value, stored_at, size) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT
+                DO
+                UPDATE SET value = ?2, publisher = NULL, published_at = NULL, signature = NULL,
+                kind = NULL, stored_at = ?3, size = ?4
+                WHERE key = ?1",
+            )?
+            .execute(params![
+                key,
+                value,
+                chrono::Utc::now().timestamp_millis(),
+                value.len()
+            ])?;
         if num == 0 {
             logWarn!("didn't insert");
         } else {
-            logInfo!("inserted successfully");
+            logDebug!("inserted successfully");
         }
         Ok(())
     }
 
     fn get(&self, key: &str) -> StorageResult<Option<String>> {
-        let conn = Connection::open(self.db_name.clone())?;
+        let conn = self.reader()?;
         Ok(conn
-            .query_row(
-                "SELECT value FROM data WHERE key = ?1",
-                params![key],
-                |row| row.get(0),
-            )
+            .prepare_cached("SELECT value FROM data WHERE key = ?1")?
+            .query_row(params![key], |row| row.get(0))
             .optional()?)
     }
 
     fn remove(&self, key: &str) -> StorageResult<()> {
-        let conn = Connection::open(self.db_name.clone())?;
-        let num_of_rows = conn.execute("DELETE FROM data WHERE key = ?1", params![key])?;
+        let conn = self.connection()?;
+        let num_of_rows = conn
+            .prepare_cached("DELETE FROM data WHERE key = ?1")?
+            .execute(params![key])?;
         if num_of_rows > 0 {
-            logInfo!("deleted the pair of key : {} ", key);
+            logDebug!("deleted the pair of key : {} ", key);
         } else {
-            logWarn!("no such key");
+            logDebug!("no such key");
         }
         Ok(())
     }
@@ -110,10 +461,158 @@ impl Storage for SqlLiteStorage {
         self.get(key).map(|opt| opt.is_some())
     }
 
-    fn list_all(&self) -> StorageResult<Vec<(String, String)>> {
-        let conn = Connection::open(self.db_name.clone())?;
-        let mut stmt = conn.prepare("SELECT key, value FROM data")?;
-        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    fn list(
+        &self,
+        range: &KeyRange,
+        after: Option<&str>,
+        limit: usize,
+    ) -> StorageResult<Page<String>> {
+        let (clause, mut args) = Self::range_clause(range, after);
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT key, value FROM data {} ORDER BY key LIMIT ?{}",
+            clause,
+            args.len() + 1
+        ))?;
+        args.push(Box::new(limit as i64));
+        let params: Vec<&dyn ToSql> = args.iter().map(|arg| arg.as_ref()).collect();
+        let rows = stmt.query_map(params.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+        Ok(Page::new(entries, limit))
+    }
+
+    fn count(&self, range: &KeyRange) -> StorageResult<usize> {
+        let (clause, args) = Self::range_clause(range, None);
+        let conn = self.reader()?;
+        let params: Vec<&dyn ToSql> = args.iter().map(|arg| arg.as_ref()).collect();
+        Ok(conn
+            .prepare_cached(&format!("SELECT COUNT(*) FROM data {}", clause))?
+            .query_row(params.as_slice(), |row| row.get(0))?)
+    }
+
+    fn stats(&self) -> StorageResult<StorageStats> {
+        let conn = self.reader()?;
+        let (entries, records, value_bytes) = conn
+            .prepare_cached("SELECT COUNT(*), COUNT(publisher), COALESCE(SUM(size), 0) FROM data")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        let tombstones = conn
+            .prepare_cached("SELECT COUNT(*) FROM tombstones")?
+            .query_row([], |row| row.get(0))?;
+        let providers = conn
+            .prepare_cached("SELECT COUNT(*) FROM providers")?
+            .query_row([], |row| row.get(0))?;
+        Ok(StorageStats {
+            entries,
+            records,
+            value_bytes,
+            tombstones,
+            providers,
+        })
+    }
+
+    fn for_each_entry(
+        &self,
+        visit: &mut dyn FnMut(String, String, Option<Record>) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, value, publisher, published_at, signature, kind FROM data ORDER BY key",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let publisher: Option<Vec<u8>> = row.get(2)?;
+            let record = match publisher {
+                Some(_) => Some(Self::row_to_record(row)?),
+                None => None,
+            };
+            visit(row.get(0)?, row.get(1)?, record)?;
+        }
+        Ok(())
+    }
+
+    fn store_record(&self, record: &Record) -> StorageResult<()> {
+        let conn = self.connection()?;
+        Self::insert_record(&conn, record)?;
+        logDebug!("inserted record for key {} successfully", record.key);
+        Ok(())
+    }
+
+    // one transaction for the whole batch, a single WAL commit instead of one per record
+    fn store_records(&self, records: &[Record]) -> StorageResult<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        for record in records {
+            Self::insert_record(&tx, record)?;
+        }
+        tx.commit()?;
+        logDebug!("inserted {} records successfully", records.len());
+        Ok(())
+    }
+
+    fn get_record(&self, key: &str) -> StorageResult<Option<Record>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .prepare_cached(
+                "SELECT key, value, publisher, published_at, signature, kind FROM data
+                WHERE key = ?1 AND publisher IS NOT NULL",
+            )?
+            .query_row(params![key], Self::row_to_record)
+            .optional()?)
+    }
+
+    fn store_tombstone(&self, tombstone: &Tombstone) -> StorageResult<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO tombstones (key, publisher, deleted_at, expires_at, signature)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT
+            DO
+            UPDATE SET publisher = ?2, deleted_at = ?3, expires_at = ?4, signature = ?5
+            WHERE key = ?1",
+        )?
+        .execute(params![
+            tombstone.key,
+            tombstone.publisher.0,
+            tombstone.deleted_at,
+            tombstone.expires_at,
+            tombstone.signature.0
+        ])?;
+        tx.prepare_cached("DELETE FROM data WHERE key = ?1")?
+            .execute(params![tombstone.key])?;
+        tx.commit()?;
+        logDebug!("stored tombstone for key {}", tombstone.key);
+        Ok(())
+    }
+
+    fn get_tombstone(&self, key: &str) -> StorageResult<Option<Tombstone>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .prepare_cached(
+                "SELECT key, publisher, deleted_at, expires_at, signature FROM tombstones
+                WHERE key = ?1",
+            )?
+            .query_row(params![key], Self::row_to_tombstone)
+            .optional()?)
+    }
+
+    fn remove_tombstone(&self, key: &str) -> StorageResult<()> {
+        let conn = self.connection()?;
+        conn.prepare_cached("DELETE FROM tombstones WHERE key = ?1")?
+            .execute(params![key])?;
+        Ok(())
+    }
+
+    fn list_tombstones(&self) -> StorageResult<Vec<Tombstone>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, publisher, deleted_at, expires_at, signature FROM tombstones",
+        )?;
+        let rows = stmt.query_map([], Self::row_to_tombstone)?;
 
         let mut results = Vec::new();
         for row in rows {
@@ -121,4 +620,422 @@ impl Storage for SqlLiteStorage {
         }
         Ok(results)
     }
+
+    fn remove_expired_tombstones(&self, now: i64) -> StorageResult<usize> {
+        let conn = self.connection()?;
+        Ok(conn
+            .prepare_cached("DELETE FROM tombstones WHERE expires_at <= ?1")?
+            .execute(params![now])?)
+    }
+
+    fn add_provider(&self, key: &str, provider: &Contact, expires_at: i64) -> StorageResult<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO providers (key, node_id, ip_address, port, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT
+            DO
+            UPDATE SET ip_address = ?3, port = ?4, expires_at = ?5",
+        )?
+        .execute(params![
+            key,
+            provider.node_id.0,
+            provider.ip_address.to_string(),
+            provider.port,
+            expires_at
+        ])?;
+        tx.prepare_cached(
+            "DELETE FROM providers WHERE key = ?1 AND node_id NOT IN (
+                SELECT node_id FROM providers WHERE key = ?1
+                ORDER BY expires_at DESC LIMIT ?2
+            )",
+        )?
+        .execute(params![key, config::get().records.max_providers_per_key])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_providers(&self, key: &str, now: i64) -> StorageResult<Vec<Contact>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT node_id, ip_address, port FROM providers
+            WHERE key = ?1 AND expires_at > ?2
+            ORDER BY expires_at DESC",
+        )?;
+        let rows = stmt.query_map(params![key, now], |row| {
+            let ip_address: String = row.get(1)?;
+            Ok((SHA(row.get(0)?), ip_address, row.get(2)?))
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let (node_id, ip_address, port) = row?;
+            // skip rows whose address doesn't parse rather than failing the whole lookup
+            if let Ok(ip_address) = ip_address.parse() {
+                results.push(Contact {
+                    node_id,
+                    ip_address,
+                    port,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    fn remove_expired_providers(&self, now: i64) -> StorageResult<usize> {
+        let conn = self.connection()?;
+        Ok(conn
+            .prepare_cached("DELETE FROM providers WHERE expires_at <= ?1")?
+            .execute(params![now])?)
+    }
+
+    // on top of the record checks, runs SQLite's own integrity check and compares each value
+    // with the size it had when stored. Damaged rows are kept in the quarantine table with the
+    // reason, and reported until release_quarantined is called for their key
+    fn verify(&self) -> StorageResult<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut conn = self.connection()?;
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        for row in rows {
+            let message = row?;
+            if message != "ok" {
+                logError!("Database integrity check: {}", message);
+                report.integrity_errors.push(message);
+            }
+        }
+        drop(stmt);
+
+        let mut damaged: Vec<(String, String)> = Vec::new();
+        let mut stmt = conn.prepare(
+            "SELECT key, value, publisher, published_at, signature, kind, size FROM data",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            report.checked += 1;
+            let key: String = row.get(0)?;
+            let reason = match Self::check_row(row) {
+                Ok(reason) => reason,
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(reason) = reason {
+                damaged.push((key, reason));
+            }
+        }
+        drop(rows);
+        drop(stmt);
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let tx = conn.transaction()?;
+        for (key, reason) in &damaged {
+            logWarn!("Quarantining the record for key {}: {}", key, reason);
+            tx.prepare_cached(
+                "INSERT OR REPLACE INTO quarantine
+                SELECT key, value, publisher, published_at, signature, kind, ?2, ?3
+                FROM data WHERE key = ?1",
+            )?
+            .execute(params![key, reason, now])?;
+            tx.prepare_cached("DELETE FROM data WHERE key = ?1")?
+                .execute(params![key])?;
+        }
+        let tombstones = {
+            let mut stmt = tx.prepare(
+                "SELECT key, publisher, deleted_at, expires_at, signature FROM tombstones",
+            )?;
+            stmt.query_map([], Self::row_to_tombstone)?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for tombstone in tombstones {
+            report.checked += 1;
+            if !tombstone.verify() {
+                logWarn!("Dropping the damaged tombstone for key {}", tombstone.key);
+                tx.prepare_cached("DELETE FROM tombstones WHERE key = ?1")?
+                    .execute(params![tombstone.key])?;
+            }
+        }
+        tx.commit()?;
+
+        // also the ones from earlier runs that couldn't be fetched again yet
+        let mut stmt = conn.prepare_cached(
+            "SELECT key FROM quarantine WHERE key NOT IN (SELECT key FROM data) ORDER BY key",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        for row in rows {
+            report.quarantined.push(row?);
+        }
+        Ok(report)
+    }
+
+    fn release_quarantined(&self, key: &str) -> StorageResult<()> {
+        let conn = self.connection()?;
+        conn.prepare_cached("DELETE FROM quarantine WHERE key = ?1")?
+            .execute(params![key])?;
+        Ok(())
+    }
+
+    // moves the WAL into the database file, so it is complete on its own
+    fn flush(&self) -> StorageResult<()> {
+        let conn = self.connection()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::memory_storage::MemoryStorage;
+    use std::path::PathBuf;
+
+    // a fresh directory for an on-disk backend, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "kade-test-{}-{}-{}",
+                name,
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn contact(id: &str, port: u16) -> Contact {
+        Contact {
+            node_id: SHA::hash_string(&id.to_string()),
+            ip_address: "127.0.0.1".parse().unwrap(),
+            port,
+        }
+    }
+
+    // contacts aren't comparable as a whole
+    fn ids(contacts: &[Contact]) -> Vec<(SHA, u16)> {
+        contacts
+            .iter()
+            .map(|contact| (contact.node_id, contact.port))
+            .collect()
+    }
+
+    // what every backend has to do the same way, the node can't tell them apart
+    fn conformance<T: Storage>(storage: &T) {
+        // plain pairs
+        storage.store("b", &"2".to_string()).unwrap();
+        storage.store("a", &"1".to_string()).unwrap();
+        storage.store("a", &"one".to_string()).unwrap();
+        assert_eq!(storage.get("a").unwrap(), Some("one".to_string()));
+        assert_eq!(storage.get("missing").unwrap(), None);
+        assert!(storage.contains("b").unwrap());
+        assert_eq!(storage.get_record("a").unwrap(), None);
+        storage.remove("b").unwrap();
+        assert!(!storage.contains("b").unwrap());
+
+        // signed records
+        let identity = Identity::generate();
+        let record = Record::new(&identity, "r".to_string(), "value".to_string());
+        let mutable = Record::new_mutable(&identity, "salt".to_string(), 3, "m".to_string());
+        storage
+            .store_records(&[record.clone(), mutable.clone()])
+            .unwrap();
+        assert_eq!(storage.get_record("r").unwrap(), Some(record.clone()));
+        assert_eq!(storage.get("r").unwrap(), Some("value".to_string()));
+        assert_eq!(
+            storage.get_record(&mutable.key).unwrap(),
+            Some(mutable.clone())
+        );
+
+        // listing in key order, a page at a time
+        for key in ["p/1", "p/2", "p/3", "q/1"] {
+            storage.store(key, &key.to_string()).unwrap();
+        }
+        let range = KeyRange::prefix("p/");
+        let page = storage.list(&range, None, 2).unwrap();
+        let keys: Vec<_> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["p/1", "p/2"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("p/2"));
+        let page = storage
+            .list(&range, page.next_cursor.as_deref(), 2)
+            .unwrap();
+        let keys: Vec<_> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["p/3"]);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(storage.count(&range).unwrap(), 3);
+        let range = KeyRange {
+            start: Some("p/2".to_string()),
+            end: Some("q/1".to_string()),
+            ..KeyRange::default()
+        };
+        assert_eq!(storage.count(&range).unwrap(), 2);
+        assert_eq!(storage.count(&KeyRange::prefix("z")).unwrap(), 0);
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.entries, 7);
+        assert_eq!(stats.records, 2);
+        let mut visited = Vec::new();
+        storage
+            .for_each_entry(&mut |key, _, record| {
+                visited.push((key, record.is_some()));
+                Ok(())
+            })
+            .unwrap();
+        visited.sort();
+        assert_eq!(visited.len(), 7);
+        assert!(visited.contains(&("r".to_string(), true)));
+        assert!(visited.contains(&("a".to_string(), false)));
+
+        // tombstones replace what they delete, and expire
+        let tombstone = Tombstone::new(&identity, "r".to_string(), 60);
+        storage.store_tombstone(&tombstone).unwrap();
+        assert_eq!(storage.get_tombstone("r").unwrap(), Some(tombstone.clone()));
+        assert_eq!(storage.get_record("r").unwrap(), None);
+        assert!(!storage.contains("r").unwrap());
+        let expired = Tombstone::new(&identity, "gone".to_string(), -1);
+        storage.store_tombstone(&expired).unwrap();
+        assert_eq!(storage.list_tombstones().unwrap().len(), 2);
+        let now = chrono::Utc::now().timestamp_millis();
+        assert_eq!(storage.remove_expired_tombstones(now).unwrap(), 1);
+        assert_eq!(storage.list_tombstones().unwrap(), vec![tombstone]);
+        storage.remove_tombstone("r").unwrap();
+        assert_eq!(storage.get_tombstone("r").unwrap(), None);
+
+        // providers, newest expiry first, a provider announcing again is updated in place
+        storage
+            .add_provider("k", &contact("x", 1), now + 1000)
+            .unwrap();
+        storage
+            .add_provider("k", &contact("y", 2), now + 2000)
+            .unwrap();
+        storage
+            .add_provider("k", &contact("x", 3), now + 3000)
+            .unwrap();
+        storage
+            .add_provider("k", &contact("z", 4), now - 1)
+            .unwrap();
+        assert_eq!(
+            ids(&storage.get_providers("k", now).unwrap()),
+            ids(&[contact("x", 3), contact("y", 2)])
+        );
+        assert!(storage.get_providers("other", now).unwrap().is_empty());
+        assert_eq!(storage.remove_expired_providers(now + 2000).unwrap(), 2);
+        assert_eq!(
+            ids(&storage.get_providers("k", now).unwrap()),
+            ids(&[contact("x", 3)])
+        );
+
+        let max = config::get().records.max_providers_per_key;
+        for port in 0..max as u16 + 2 {
+            let id = format!("many{}", port);
+            let expires_at = now + 10_000 + port as i64;
+            storage
+                .add_provider("many", &contact(&id, port), expires_at)
+                .unwrap();
+        }
+        let providers = storage.get_providers("many", now).unwrap();
+        assert_eq!(providers.len(), max);
+        // the ones closest to expiring were evicted
+        assert_eq!(
+            ids(&providers[..1]),
+            ids(&[contact(&format!("many{}", max + 1), max as u16 + 1)])
+        );
+        assert!(!ids(&providers).contains(&ids(&[contact("many0", 0)])[0]));
+
+        storage.flush().unwrap();
+    }
+
+    #[test]
+    fn prefix_end_is_the_next_key_after_the_prefix() {
+        assert_eq!(prefix_end("abc").as_deref(), Some("abd"));
+        assert_eq!(prefix_end("a\u{ff}").as_deref(), Some("a\u{100}"));
+        assert_eq!(prefix_end("a\u{d7ff}").as_deref(), Some("a\u{e000}"));
+        assert_eq!(prefix_end("a\u{10ffff}").as_deref(), Some("b"));
+        assert_eq!(prefix_end("\u{10ffff}\u{10ffff}"), None);
+        assert_eq!(prefix_end(""), None);
+        // every key with the prefix sorts before it
+        let end = prefix_end("a\u{ff}").unwrap();
+        for key in ["a\u{ff}", "a\u{ff}\u{ff}", "a\u{ff}\u{10ffff}"] {
+            assert!(key < end.as_str(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn key_range_bounds() {
+        let some = |key: &str| Some(key.to_string());
+        assert_eq!(KeyRange::default().bounds(), (None, None));
+        assert_eq!(KeyRange::prefix("p").bounds(), (some("p"), some("q")));
+        assert_eq!(
+            KeyRange::prefix("\u{10ffff}").bounds(),
+            (some("\u{10ffff}"), None)
+        );
+
+        // start and end narrow the prefix, never widen it
+        let range = KeyRange {
+            start: some("a"),
+            end: some("z"),
+            ..KeyRange::prefix("p")
+        };
+        assert_eq!(range.bounds(), (some("p"), some("q")));
+        let range = KeyRange {
+            start: some("p5"),
+            end: some("p7"),
+            ..KeyRange::prefix("p")
+        };
+        assert_eq!(range.bounds(), (some("p5"), some("p7")));
+    }
+
+    #[test]
+    fn key_bounds_start_after_the_cursor() {
+        let range = KeyRange {
+            start: Some("b".to_string()),
+            end: Some("d".to_string()),
+            ..KeyRange::default()
+        };
+        assert_eq!(
+            range.key_bounds(None),
+            (
+                Bound::Included("b".to_string()),
+                Bound::Excluded("d".to_string())
+            )
+        );
+        assert_eq!(
+            range.key_bounds(Some("c")).0,
+            Bound::Excluded("c".to_string())
+        );
+        // a cursor before the start doesn't widen the range
+        assert_eq!(
+            range.key_bounds(Some("a")).0,
+            Bound::Included("b".to_string())
+        );
+        assert_eq!(
+            KeyRange::default().key_bounds(None),
+            (Bound::Unbounded, Bound::Unbounded)
+        );
+    }
+
+    #[test]
+    fn memory_storage_conforms() {
+        conformance(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn sqlite_storage_conforms() {
+        let dir = TempDir::new("sqlite");
+        conformance(&SqlLiteStorage::new(dir.0.join("storage.sqlite3")).unwrap());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn sled_storage_conforms() {
+        let dir = TempDir::new("sled");
+        conformance(&crate::sled_storage::SledStorage::new(dir.0.join("storage.sled")).unwrap());
+    }
 }
@@ -0,0 +1,48 @@
+use crate::contact::Contact;
+use std::fmt;
+
+// the outcome of a STORE sent to the k closest nodes of a key,
+// every target ends up in exactly one of confirmed, rejected or timed_out
+#[derive(Debug, Clone)]
+pub struct StoreReport {
+    pub key: String,
+    pub targets: Vec<Contact>,
+    pub confirmed: Vec<Contact>,
+    pub rejected: Vec<(Contact, String)>,
+    pub timed_out: Vec<Contact>,
+}
+
+impl StoreReport {
+    pub fn new(key: String, targets: Vec<Contact>) -> Self {
+        Self {
+            key,
+            targets,
+            confirmed: Vec::new(),
+            rejected: Vec::new(),
+            timed_out: Vec::new(),
+        }
+    }
+
+    // number of nodes that acknowledged saving the value
+    pub fn replicas(&self) -> usize {
+        self.confirmed.len()
+    }
+
+    pub fn meets(&self, min_replicas: usize) -> bool {
+        self.replicas() >= min_replicas
+    }
+}
+
+impl fmt::Display for StoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key {} confirmed by {}/{} nodes ({} rejected, {} timed out)",
+            self.key,
+            self.replicas(),
+            self.targets.len(),
+            self.rejected.len(),
+            self.timed_out.len()
+        )
+    }
+}
@@ -0,0 +1,84 @@
+use crate::config;
+use crate::sha::SHA;
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+// an opaque proof that the requester talked to us recently from its address,
+// it has to come back with every write (STORE, DELETE, ADD_PROVIDER)
+#[derive(Copy, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Token(pub SHA);
+
+// issues and checks write tokens, like the tokens of BitTorrent's get_peers:
+// token = hash(secret of the current period || ip), where each period's secret is derived
+// from a random seed that never leaves the process, tokens of the previous period are still
+// accepted so that a token is valid for one to two periods
+#[derive(Debug)]
+pub struct TokenSecret {
+    seed: [u8; 32],
+}
+
+impl Default for TokenSecret {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenSecret {
+    pub fn new() -> Self {
+        let mut seed = [0u8; 32];
+        rand::rng().fill(&mut seed);
+        Self { seed }
+    }
+
+    pub fn issue(&self, ip_address: &IpAddr) -> Token {
+        self.token_for(Self::current_period(), ip_address)
+    }
+
+    pub fn validate(&self, ip_address: &IpAddr, token: &Token) -> bool {
+        let period = Self::current_period();
+        self.token_for(period, ip_address) == *token
+            || self.token_for(period - 1, ip_address) == *token
+    }
+
+    fn current_period() -> i64 {
+        Utc::now().timestamp() / config::get().records.token_rotation_secs as i64
+    }
+
+    fn token_for(&self, period: i64, ip_address: &IpAddr) -> Token {
+        let mut secret_input = self.seed.to_vec();
+        secret_input.extend_from_slice(&period.to_be_bytes());
+        let secret = SHA::hash(&secret_input);
+
+        let mut token_input = secret.0.to_vec();
+        token_input.extend_from_slice(ip_address.to_string().as_bytes());
+        Token(SHA::hash(&token_input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_bound_to_the_address() {
+        let secret = TokenSecret::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let token = secret.issue(&ip);
+        assert!(secret.validate(&ip, &token));
+        assert!(!secret.validate(&other, &token));
+        assert!(!TokenSecret::new().validate(&ip, &token));
+    }
+
+    #[test]
+    fn tokens_outlive_one_rotation_but_not_two() {
+        let secret = TokenSecret::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let period = TokenSecret::current_period();
+        assert!(secret.validate(&ip, &secret.token_for(period - 1, &ip)));
+        assert!(!secret.validate(&ip, &secret.token_for(period - 2, &ip)));
+        assert!(!secret.validate(&ip, &secret.token_for(period + 1, &ip)));
+    }
+}